@@ -0,0 +1,196 @@
+//! Optional compression before encryption.
+//!
+//! An engine with a [`Compression`] codec (see
+//! [`CryptoEngine::with_compression`](crate::CryptoEngine::with_compression))
+//! compresses each message before sealing it and records the codec in the
+//! record header ([`FLAG_LZ4`](crate::format::FLAG_LZ4) or
+//! [`FLAG_ZSTD`](crate::format::FLAG_ZSTD)). Messages that do not shrink are
+//! sealed as they are, without the flag. Receivers decompress whenever the
+//! flag is set, up to their
+//! [decompression limit](crate::CryptoEngine::with_decompression_limit), so
+//! an authenticated but hostile peer cannot exhaust memory with a
+//! decompression bomb.
+//!
+//! Codecs are behind the `lz4` and `zstd` cargo features, and no engine
+//! compresses unless asked to. Compressed sizes depend on the content, so
+//! when attacker-chosen data is encrypted together with secrets, record
+//! lengths can leak those secrets (CRIME, BREACH). Only compress traffic
+//! where that cannot happen, such as config sync or logs from one source.
+
+use crate::error::CryptoError;
+use crate::format::{FLAG_LZ4, FLAG_ZSTD};
+
+/// Default cap on the decompressed size of a single record.
+pub const DEFAULT_DECOMPRESSION_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Compression codec applied to messages before encryption.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// LZ4 block compression: fast, moderate ratio.
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// zstd at the given level (`0` for the library default): slower,
+    /// better ratio.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+impl Compression {
+    /// Returns the header flag recording this codec.
+    pub fn flag(self) -> u16 {
+        match self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => FLAG_LZ4,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(_) => FLAG_ZSTD,
+        }
+    }
+
+    #[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    fn compress(self, data: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Some(lz4_flex::block::compress_prepend_size(data)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => zstd::stream::encode_all(data, level).ok(),
+        }
+    }
+}
+
+/// Compresses the `len`-byte message at the front of `buf` in place if that
+/// makes it shorter. Returns the new length and the header flag to set, or
+/// the old length and `0` if the message was left alone.
+pub(crate) fn compress_in_place(
+    compression: Compression,
+    buf: &mut [u8],
+    len: usize,
+) -> (usize, u16) {
+    match compression.compress(&buf[..len]) {
+        Some(compressed) if compressed.len() < len => {
+            buf[..compressed.len()].copy_from_slice(&compressed);
+            (compressed.len(), compression.flag())
+        }
+        _ => (len, 0),
+    }
+}
+
+/// Decompresses `data` according to the compression flag in `flags`.
+/// Returns `None` if the record is not compressed.
+#[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+pub(crate) fn decompress(
+    flags: u16,
+    data: &[u8],
+    limit: usize,
+) -> Result<Option<Vec<u8>>, CryptoError> {
+    match flags & (FLAG_LZ4 | FLAG_ZSTD) {
+        0 => Ok(None),
+        #[cfg(feature = "lz4")]
+        FLAG_LZ4 => decompress_lz4(data, limit).map(Some),
+        #[cfg(feature = "zstd")]
+        FLAG_ZSTD => decompress_zstd(data, limit).map(Some),
+        _ => Err(CryptoError::UnsupportedCompression),
+    }
+}
+
+#[cfg(feature = "lz4")]
+fn decompress_lz4(data: &[u8], limit: usize) -> Result<Vec<u8>, CryptoError> {
+    let (size, block) =
+        lz4_flex::block::uncompressed_size(data).map_err(|_| CryptoError::DecompressionFailed)?;
+    if size > limit {
+        return Err(CryptoError::DecompressedTooLarge { limit });
+    }
+    let mut output = vec![0u8; size];
+    match lz4_flex::block::decompress_into(block, &mut output) {
+        Ok(len) if len == size => Ok(output),
+        _ => Err(CryptoError::DecompressionFailed),
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn decompress_zstd(data: &[u8], limit: usize) -> Result<Vec<u8>, CryptoError> {
+    use std::io::Read;
+
+    let decoder = zstd::stream::read::Decoder::with_buffer(data)
+        .map_err(|_| CryptoError::DecompressionFailed)?;
+    // Read at most one byte past the limit, so a bomb is caught without
+    // inflating it.
+    let mut output = Vec::new();
+    decoder
+        .take(limit as u64 + 1)
+        .read_to_end(&mut output)
+        .map_err(|_| CryptoError::DecompressionFailed)?;
+    if output.len() > limit {
+        return Err(CryptoError::DecompressedTooLarge { limit });
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uncompressed_records_pass_through() {
+        assert_eq!(decompress(0, b"data", 16), Ok(None));
+    }
+
+    #[cfg(not(feature = "lz4"))]
+    #[test]
+    fn unsupported_codec_is_rejected() {
+        assert_eq!(
+            decompress(FLAG_LZ4, b"data", 16),
+            Err(CryptoError::UnsupportedCompression)
+        );
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_round_trip_and_limit() {
+        let message = vec![b'a'; 1000];
+        let mut buf = message.clone();
+        let (len, flag) = compress_in_place(Compression::Lz4, &mut buf, message.len());
+        assert!(len < message.len());
+        assert_eq!(flag, FLAG_LZ4);
+        assert_eq!(decompress(flag, &buf[..len], 1000), Ok(Some(message)));
+        assert_eq!(
+            decompress(flag, &buf[..len], 999),
+            Err(CryptoError::DecompressedTooLarge { limit: 999 })
+        );
+        assert_eq!(
+            decompress(flag, &buf[..len - 1], 1000),
+            Err(CryptoError::DecompressionFailed)
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_round_trip_and_limit() {
+        let message = vec![b'z'; 1000];
+        let mut buf = message.clone();
+        let (len, flag) = compress_in_place(Compression::Zstd(0), &mut buf, message.len());
+        assert!(len < message.len());
+        assert_eq!(flag, FLAG_ZSTD);
+        assert_eq!(decompress(flag, &buf[..len], 1000), Ok(Some(message)));
+        assert_eq!(
+            decompress(flag, &buf[..len], 999),
+            Err(CryptoError::DecompressedTooLarge { limit: 999 })
+        );
+        assert_eq!(
+            decompress(flag, b"not zstd", 1000),
+            Err(CryptoError::DecompressionFailed)
+        );
+    }
+
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    #[test]
+    fn incompressible_messages_are_left_alone() {
+        let message: Vec<u8> = (0..64u8).collect();
+        let mut buf = message.clone();
+        #[cfg(feature = "lz4")]
+        let compression = Compression::Lz4;
+        #[cfg(not(feature = "lz4"))]
+        let compression = Compression::Zstd(0);
+        assert_eq!(compress_in_place(compression, &mut buf, 64), (64, 0));
+        assert_eq!(buf, message);
+    }
+}
@@ -0,0 +1,974 @@
+use std::fmt;
+use std::ops::Range;
+use std::sync::Mutex;
+use zeroize::ZeroizeOnDrop;
+
+use crate::compression::{self, Compression, DEFAULT_DECOMPRESSION_LIMIT};
+use crate::error::CryptoError;
+use crate::format::{self, CipherSuite, HEADER_LEN, Header};
+use crate::kdf::{self, Argon2Params};
+use crate::key::SecretKey;
+use crate::nonce::{self, CounterNonce, NonceSource};
+use crate::padding::{self, Padding};
+use crate::replay::ReplayWindow;
+use crate::suite::AeadSuite;
+
+/// Size of the key in bytes, for every cipher suite.
+pub const KEY_LEN: usize = 32;
+
+/// Size of the XChaCha20-Poly1305 extended nonce in bytes. This is also the
+/// longest nonce any suite uses.
+pub const NONCE_LEN: usize = 24;
+
+/// Size of the authentication tag in bytes, for every cipher suite.
+pub const TAG_LEN: usize = 16;
+
+/// AEAD encryption engine bound to a single 32-byte key.
+///
+/// Output records are laid out as
+/// `[12-byte header][nonce][ciphertext + 16-byte tag]`, with a 24-byte nonce
+/// for the default XChaCha20-Poly1305 suite; see [`format`](crate::format)
+/// for the header layout.
+///
+/// The cipher's copy of the key is zeroized when the engine is dropped, and
+/// `Debug` output only shows the suite and key id.
+pub struct CryptoEngine {
+    cipher: Box<dyn AeadSuite>,
+    suite: CipherSuite,
+    key_id: u32,
+    pub(crate) nonces: NonceSource,
+    replay: Option<Mutex<ReplayWindow>>,
+    label: Vec<u8>,
+    padding: Option<Padding>,
+    compression: Option<Compression>,
+    decompression_limit: usize,
+}
+
+// Every `AeadSuite` wipes its key schedule on drop; for AES-256-GCM that
+// relies on the `zeroize` features of `aes` and `polyval` in Cargo.toml.
+impl ZeroizeOnDrop for CryptoEngine {}
+
+impl fmt::Debug for CryptoEngine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CryptoEngine")
+            .field("suite", &self.suite)
+            .field("key_id", &self.key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CryptoEngine {
+    /// Creates an XChaCha20-Poly1305 engine from a secret key with key id `0`.
+    pub fn new(key: &SecretKey) -> Self {
+        Self::with_suite(key, CipherSuite::XChaCha20Poly1305)
+    }
+
+    /// Creates an engine for an explicit cipher suite with key id `0`.
+    pub fn with_suite(key: &SecretKey, suite: CipherSuite) -> Self {
+        Self {
+            cipher: suite.cipher(key),
+            suite,
+            key_id: 0,
+            nonces: NonceSource::Random,
+            replay: None,
+            label: Vec::new(),
+            padding: None,
+            compression: None,
+            decompression_limit: DEFAULT_DECOMPRESSION_LIMIT,
+        }
+    }
+
+    /// Creates an engine whose key is derived from a passphrase with Argon2id.
+    ///
+    /// The salt must be at least [`kdf::MIN_SALT_LEN`] bytes and should be
+    /// random and stored alongside whatever the passphrase protects.
+    pub fn from_passphrase(
+        passphrase: &[u8],
+        salt: &[u8],
+        params: &Argon2Params,
+    ) -> Result<Self, CryptoError> {
+        let key = kdf::derive_from_passphrase(passphrase, salt, params)?;
+        Ok(Self::new(&key))
+    }
+
+    /// Creates an engine whose key is derived from existing keying material
+    /// with HKDF-SHA256, using `context` as the info label.
+    pub fn from_secret(secret: &[u8], context: &[u8]) -> Result<Self, CryptoError> {
+        let key = kdf::derive_from_secret(secret, None, context)?;
+        Ok(Self::new(&key))
+    }
+
+    /// Sets the key id written into, and required from, record headers.
+    pub fn with_key_id(mut self, key_id: u32) -> Self {
+        self.key_id = key_id;
+        self
+    }
+
+    /// Switches the engine to counter-based nonces with a fresh random
+    /// session prefix.
+    ///
+    /// Records then carry a sequence number receivers can check, and
+    /// encryption fails with [`CryptoError::NonceExhausted`] rather than
+    /// wrapping the counter.
+    ///
+    /// # Key reuse
+    ///
+    /// Only use this with a key that no other engine encrypts under, such
+    /// as the per-session keys from [`handshake`](crate::handshake),
+    /// [`Session`](crate::session::Session) and
+    /// [`RekeyingSession`](crate::rekey::RekeyingSession).
+    /// The counter restarts at zero for every engine, and the 12-byte-nonce
+    /// suites have only a 4-byte random prefix, so engines sharing a key
+    /// repeat nonces after about 2^16 instances. For AES-256-GCM and
+    /// ChaCha20-Poly1305 a repeated nonce reveals the authentication key.
+    /// Long-lived keys (key files, a [`Keyring`](crate::Keyring), the CLI)
+    /// should keep the default random nonces.
+    pub fn with_counter_nonces(self) -> Self {
+        self.with_nonce_counter(CounterNonce::new())
+    }
+
+    /// Switches the engine to counter-based nonces from an explicit source.
+    ///
+    /// The same key-reuse rule as for
+    /// [`with_counter_nonces`](Self::with_counter_nonces) applies.
+    pub fn with_nonce_counter(mut self, counter: CounterNonce) -> Self {
+        self.nonces = NonceSource::Counter(counter);
+        self
+    }
+
+    /// Enables receiver-side replay protection.
+    ///
+    /// Decryption then only accepts records sealed with counter nonces (see
+    /// [`with_counter_nonces`](Self::with_counter_nonces)), and rejects each
+    /// sequence number after its first successful decryption, as well as
+    /// anything older than the [`ReplayWindow`].
+    pub fn with_replay_protection(mut self) -> Self {
+        self.replay = Some(Mutex::new(ReplayWindow::new()));
+        self
+    }
+
+    /// Binds `label` into the AAD of every record, between the header and
+    /// the caller's AAD.
+    ///
+    /// Engines with different labels cannot open each other's records even
+    /// under the same key, which keeps e.g. the two directions of a
+    /// [`Session`](crate::session::Session) apart. Legacy headerless records
+    /// are opened without the label.
+    pub fn with_aad_label(mut self, label: &[u8]) -> Self {
+        self.label = label.to_vec();
+        self
+    }
+
+    /// Pads every message this engine seals according to `padding`, hiding
+    /// its exact length.
+    ///
+    /// Receivers strip the padding whether or not they have a policy
+    /// themselves; see [`padding`](crate::padding).
+    pub fn with_padding(mut self, padding: Padding) -> Self {
+        self.padding = Some(padding);
+        self
+    }
+
+    /// Compresses every message before sealing it, when that makes it
+    /// shorter.
+    ///
+    /// Off by default: see [`compression`](crate::compression) for when
+    /// compressing encrypted traffic is safe. Receivers decompress whether
+    /// or not they have a codec set themselves.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Caps the decompressed size of a received record, which is
+    /// [`DEFAULT_DECOMPRESSION_LIMIT`] bytes unless set. Larger records fail
+    /// with [`CryptoError::DecompressedTooLarge`].
+    pub fn with_decompression_limit(mut self, limit: usize) -> Self {
+        self.decompression_limit = limit;
+        self
+    }
+
+    /// Returns the cipher suite this engine seals records with.
+    pub fn suite(&self) -> CipherSuite {
+        self.suite
+    }
+
+    /// Returns the key id this engine seals records under.
+    pub fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    /// Returns the label set by [`with_aad_label`](Self::with_aad_label).
+    pub(crate) fn aad_label(&self) -> &[u8] {
+        &self.label
+    }
+
+    /// Returns the AEAD cipher behind this engine.
+    pub(crate) fn cipher(&self) -> &dyn AeadSuite {
+        self.cipher.as_ref()
+    }
+
+    /// Encrypts a UTF-8 message, authenticating `aad` alongside it.
+    ///
+    /// `aad` is typically an [`Aad`](crate::aad::Aad); a plain `&str` is
+    /// also accepted.
+    pub fn encrypt(&self, message: &str, aad: impl AsRef<[u8]>) -> Result<Vec<u8>, CryptoError> {
+        self.encrypt_bytes(message.as_bytes(), aad.as_ref())
+    }
+
+    /// Decrypts a record produced by [`encrypt`](Self::encrypt) back into a `String`.
+    pub fn decrypt(&self, data: &[u8], aad: impl AsRef<[u8]>) -> Result<String, CryptoError> {
+        let plaintext = self.decrypt_bytes(data, aad.as_ref())?;
+
+        // Convert to UTF-8 with specific error message
+        String::from_utf8(plaintext).map_err(|_| CryptoError::InvalidUtf8)
+    }
+
+    /// Encrypts an arbitrary binary payload, authenticating `aad` alongside it.
+    pub fn encrypt_bytes(&self, message: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let mut record = vec![0u8; self.record_len(message.len())];
+        let start = self.prefix_len();
+        record[start..start + message.len()].copy_from_slice(message);
+        let record_len = self.encrypt_in_place(&mut record, message.len(), aad)?;
+        record.truncate(record_len);
+        Ok(record)
+    }
+
+    /// Number of bytes [`encrypt_in_place`](Self::encrypt_in_place) needs in
+    /// front of the message for the header and nonce.
+    pub fn prefix_len(&self) -> usize {
+        HEADER_LEN + self.suite.nonce_len()
+    }
+
+    /// Total record length for a message of `message_len` bytes, including
+    /// any padding. With compression this is an upper bound.
+    pub fn record_len(&self, message_len: usize) -> usize {
+        let overhead = self.prefix_len() + TAG_LEN;
+        let padded_len = match &self.padding {
+            Some(padding) => padding.padded_len(message_len, overhead),
+            None => message_len,
+        };
+        overhead + padded_len
+    }
+
+    /// Encrypts a message inside a caller-provided buffer without allocating.
+    ///
+    /// The buffer must be laid out as
+    /// `[prefix_len() bytes of room][message_len bytes of message][room for padding and tag]`,
+    /// [`record_len`](Self::record_len) bytes in all. On success the buffer
+    /// starts with the finished record and its length is returned, which
+    /// can be less than `record_len` if compression shrank the message.
+    pub fn encrypt_in_place(
+        &self,
+        buffer: &mut [u8],
+        message_len: usize,
+        aad: &[u8],
+    ) -> Result<usize, CryptoError> {
+        let record_len = self.record_len(message_len);
+        if buffer.len() < record_len {
+            return Err(CryptoError::BufferTooSmall { needed: record_len });
+        }
+
+        self.nonces
+            .fill_nonce(&mut buffer[HEADER_LEN..self.prefix_len()])?;
+        self.seal_in_place(&mut buffer[..record_len], message_len, aad)
+    }
+
+    /// Seals `record`, whose nonce and `message_len`-byte message are
+    /// already in place, filling in the header, padding and tag around the
+    /// (possibly compressed) message. Returns the length of the sealed
+    /// record at the start of `record`.
+    pub(crate) fn seal_in_place(
+        &self,
+        record: &mut [u8],
+        message_len: usize,
+        aad: &[u8],
+    ) -> Result<usize, CryptoError> {
+        let start = self.prefix_len();
+        let (message_len, compression_flag) = match self.compression {
+            Some(compression) => compression::compress_in_place(
+                compression,
+                &mut record[start..start + message_len],
+                message_len,
+            ),
+            None => (message_len, 0),
+        };
+        let record_len = self.record_len(message_len);
+        let record = &mut record[..record_len];
+
+        let mut header = Header::new(self.suite, self.key_id);
+        header.flags = self.nonces.header_flags() | compression_flag;
+        if self.padding.is_some() {
+            header.flags |= format::FLAG_PADDED;
+        }
+        let header = header.encode();
+
+        let (prefix, body) = record.split_at_mut(self.prefix_len());
+        prefix[..HEADER_LEN].copy_from_slice(&header);
+        let nonce = &prefix[HEADER_LEN..];
+        let (message, tag) = body.split_at_mut(body.len() - TAG_LEN);
+        if self.padding.is_some() {
+            padding::pad(message, message_len);
+        }
+
+        let bound = BoundAad::new(&[&header, &self.label, aad]);
+        tag.copy_from_slice(&self.cipher.seal_in_place(nonce, bound.as_ref(), message)?);
+        Ok(record_len)
+    }
+
+    /// Decrypts a record produced by [`encrypt_bytes`](Self::encrypt_bytes).
+    ///
+    /// Legacy headerless records (`[nonce][ciphertext + tag]`) are still
+    /// accepted. A legacy nonce can start with the header magic by chance,
+    /// so a record whose header does not parse or does not match this
+    /// engine is retried as legacy before the original error is reported.
+    /// Once a header matches, its errors are reported without a retry.
+    pub fn decrypt_bytes(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let mut plaintext = data.to_vec();
+        let (range, flags) = self.open_in_place(&mut plaintext, aad)?;
+        if let Some(decompressed) =
+            compression::decompress(flags, &plaintext[range.clone()], self.decompression_limit)?
+        {
+            return Ok(decompressed);
+        }
+        plaintext.truncate(range.end);
+        plaintext.drain(..range.start);
+        Ok(plaintext)
+    }
+
+    /// Decrypts a record inside `record` without allocating and returns the
+    /// plaintext as a sub-slice of it.
+    ///
+    /// Accepts the same records as [`decrypt_bytes`](Self::decrypt_bytes).
+    /// The buffer is left untouched if authentication fails. Compressed
+    /// records are decompressed after the header and nonce, and fail with
+    /// [`CryptoError::BufferTooSmall`] if `record` has no room for that.
+    pub fn decrypt_in_place<'a>(
+        &self,
+        record: &'a mut [u8],
+        aad: &[u8],
+    ) -> Result<&'a mut [u8], CryptoError> {
+        let (range, flags) = self.open_in_place(record, aad)?;
+        match compression::decompress(flags, &record[range.clone()], self.decompression_limit)? {
+            Some(decompressed) => {
+                let end = range.start + decompressed.len();
+                if end > record.len() {
+                    return Err(CryptoError::BufferTooSmall { needed: end });
+                }
+                record[range.start..end].copy_from_slice(&decompressed);
+                Ok(&mut record[range.start..end])
+            }
+            None => Ok(&mut record[range]),
+        }
+    }
+
+    /// Authenticates and decrypts `data`, returning where the payload is
+    /// and the header flags describing it (`0` for legacy records).
+    fn open_in_place(
+        &self,
+        data: &mut [u8],
+        aad: &[u8],
+    ) -> Result<(Range<usize>, u16), CryptoError> {
+        if !format::has_header(data) {
+            return self.open_legacy(data, aad).map(|range| (range, 0));
+        }
+        match self.matching_header(data) {
+            // Only a header this engine cannot use may be a legacy nonce that
+            // starts with the magic; nothing has been decrypted yet.
+            Err(e) => self
+                .open_legacy(data, aad)
+                .map(|range| (range, 0))
+                .map_err(|_| e),
+            Ok(header) => self.open_headered(header, data, aad),
+        }
+    }
+
+    /// Parses the header of `data` and checks it was written for this engine.
+    fn matching_header(&self, data: &[u8]) -> Result<Header, CryptoError> {
+        let header = Header::parse(data)?;
+        if header.key_id != self.key_id {
+            return Err(CryptoError::UnknownKeyId {
+                key_id: header.key_id,
+            });
+        }
+        if header.suite != self.suite {
+            return Err(CryptoError::SuiteMismatch {
+                suite: header.suite.id(),
+            });
+        }
+        Ok(header)
+    }
+
+    fn open_headered(
+        &self,
+        header: Header,
+        data: &mut [u8],
+        aad: &[u8],
+    ) -> Result<(Range<usize>, u16), CryptoError> {
+        let data_len = data.len();
+        let (header_bytes, body) = data.split_at_mut(HEADER_LEN);
+        let nonce_len = self.suite.nonce_len();
+        // An empty message still carries a tag.
+        if body.len() < nonce_len + TAG_LEN {
+            return Err(CryptoError::TruncatedInput { len: data_len });
+        }
+        let (nonce, ciphertext) = body.split_at_mut(nonce_len);
+
+        let sequence = match &self.replay {
+            Some(window) => {
+                if header.flags & format::FLAG_COUNTER_NONCE == 0 {
+                    return Err(CryptoError::MissingSequenceNumber);
+                }
+                let sequence = nonce::counter_of(nonce);
+                // Cheap early drop; the authoritative check is after decryption.
+                lock(window).check(sequence)?;
+                Some(sequence)
+            }
+            None => None,
+        };
+
+        let bound = BoundAad::new(&[header_bytes, &self.label, aad]);
+        let mut plaintext_len = self.open_detached(nonce, bound.as_ref(), ciphertext)?;
+        if header.flags & format::FLAG_PADDED != 0 {
+            plaintext_len = padding::unpadded_len(&ciphertext[..plaintext_len])?;
+        }
+
+        // Only authenticated records may advance the window.
+        if let (Some(window), Some(sequence)) = (&self.replay, sequence) {
+            lock(window).update(sequence)?;
+        }
+        let start = HEADER_LEN + nonce_len;
+        Ok((start..start + plaintext_len, header.flags))
+    }
+
+    fn open_legacy(&self, data: &mut [u8], aad: &[u8]) -> Result<Range<usize>, CryptoError> {
+        // Legacy records have no sequence number to check
+        if self.replay.is_some() {
+            return Err(CryptoError::MissingSequenceNumber);
+        }
+        // Only XChaCha20-Poly1305 predates the header
+        if self.suite != CipherSuite::XChaCha20Poly1305 {
+            return Err(CryptoError::InvalidHeader);
+        }
+
+        // Validate input: ensure data has minimum length for nonce + tag
+        if data.len() < NONCE_LEN + TAG_LEN {
+            return Err(CryptoError::TruncatedInput { len: data.len() });
+        }
+
+        let (nonce, ciphertext) = data.split_at_mut(NONCE_LEN);
+
+        let plaintext_len = self.open_detached(nonce, aad, ciphertext)?;
+        Ok(NONCE_LEN..NONCE_LEN + plaintext_len)
+    }
+
+    /// Splits the tag off `ciphertext`, then verifies and decrypts the rest in
+    /// place. Returns the plaintext length.
+    fn open_detached(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        ciphertext: &mut [u8],
+    ) -> Result<usize, CryptoError> {
+        let plaintext_len = ciphertext
+            .len()
+            .checked_sub(TAG_LEN)
+            .ok_or(CryptoError::AuthenticationFailed)?;
+        let (message, tag) = ciphertext.split_at_mut(plaintext_len);
+        let tag: &[u8; TAG_LEN] = (&*tag).try_into().expect("tag is TAG_LEN bytes");
+        self.cipher.open_in_place(nonce, aad, message, tag)?;
+        Ok(plaintext_len)
+    }
+}
+
+/// Locks the replay window. A panic while holding the lock cannot leave the
+/// bitmap half-updated, so a poisoned lock is still safe to use.
+fn lock(window: &Mutex<ReplayWindow>) -> std::sync::MutexGuard<'_, ReplayWindow> {
+    window
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Associated data for a headered record: the encoded header, the engine's
+/// AAD label and the caller's AAD. The header has a fixed length and the
+/// label is fixed per engine, so the concatenation is unambiguous. Short
+/// AAD is assembled on the stack to keep the packet path allocation-free.
+#[allow(clippy::large_enum_variant)] // the inline variant is the point
+enum BoundAad {
+    Inline([u8; INLINE_AAD_LEN], usize),
+    Heap(Vec<u8>),
+}
+
+const INLINE_AAD_LEN: usize = 256;
+
+impl BoundAad {
+    fn new(parts: &[&[u8]]) -> Self {
+        let len = parts.iter().map(|part| part.len()).sum();
+        if len <= INLINE_AAD_LEN {
+            let mut inline = [0u8; INLINE_AAD_LEN];
+            let mut offset = 0;
+            for part in parts {
+                inline[offset..offset + part.len()].copy_from_slice(part);
+                offset += part.len();
+            }
+            BoundAad::Inline(inline, len)
+        } else {
+            BoundAad::Heap(parts.concat())
+        }
+    }
+}
+
+impl AsRef<[u8]> for BoundAad {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            BoundAad::Inline(inline, len) => &inline[..*len],
+            BoundAad::Heap(bound) => bound,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt_with_nonce(
+        engine: &CryptoEngine,
+        nonce: &[u8],
+        message: &[u8],
+        aad: &[u8],
+    ) -> Vec<u8> {
+        let mut record = vec![0u8; engine.record_len(message.len())];
+        let start = engine.prefix_len();
+        record[HEADER_LEN..start].copy_from_slice(nonce);
+        record[start..start + message.len()].copy_from_slice(message);
+        engine
+            .seal_in_place(&mut record, message.len(), aad)
+            .unwrap();
+        record
+    }
+
+    #[test]
+    fn record_starts_with_header_and_nonce() {
+        let engine = CryptoEngine::new(&SecretKey::from([0u8; KEY_LEN])).with_key_id(42);
+        let nonce = [9u8; NONCE_LEN];
+        let record = encrypt_with_nonce(&engine, &nonce, b"payload", b"");
+        assert_eq!(
+            Header::parse(&record).unwrap(),
+            Header::new(CipherSuite::XChaCha20Poly1305, 42)
+        );
+        assert_eq!(&record[HEADER_LEN..HEADER_LEN + NONCE_LEN], &nonce);
+        assert_eq!(
+            record.len(),
+            HEADER_LEN + NONCE_LEN + b"payload".len() + TAG_LEN
+        );
+    }
+
+    #[test]
+    fn debug_does_not_expose_key() {
+        let engine = CryptoEngine::new(&SecretKey::from([0x41u8; KEY_LEN])).with_key_id(3);
+        assert_eq!(
+            format!("{:?}", engine),
+            "CryptoEngine { suite: XChaCha20Poly1305, key_id: 3, .. }"
+        );
+    }
+
+    #[test]
+    fn encrypt_is_deterministic_for_fixed_nonce() {
+        let engine = CryptoEngine::new(&SecretKey::from([0u8; KEY_LEN]));
+        let nonce = [1u8; NONCE_LEN];
+        let a = encrypt_with_nonce(&engine, &nonce, b"payload", b"aad");
+        let b = encrypt_with_nonce(&engine, &nonce, b"payload", b"aad");
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn padded_records_hide_message_length() {
+        let key = SecretKey::from([0u8; KEY_LEN]);
+        let sender = CryptoEngine::new(&key).with_padding(Padding::Buckets(vec![64, 256]));
+        let receiver = CryptoEngine::new(&key);
+        for message in [&b""[..], b"a", b"ends in zeros\0\0", &[0x80; 63]] {
+            let record = sender.encrypt_bytes(message, b"aad").unwrap();
+            assert_eq!(record.len(), HEADER_LEN + NONCE_LEN + 64 + TAG_LEN);
+            assert_eq!(record.len(), sender.record_len(message.len()));
+            let flags = Header::parse(&record).unwrap().flags;
+            assert_eq!(flags & format::FLAG_PADDED, format::FLAG_PADDED);
+            assert_eq!(receiver.decrypt_bytes(&record, b"aad").unwrap(), message);
+        }
+        let record = sender.encrypt_bytes(&[7; 64], b"aad").unwrap();
+        assert_eq!(record.len(), HEADER_LEN + NONCE_LEN + 256 + TAG_LEN);
+    }
+
+    #[test]
+    fn mtu_padding_fills_the_record() {
+        let engine = CryptoEngine::with_suite(
+            &SecretKey::from([0u8; KEY_LEN]),
+            CipherSuite::ChaCha20Poly1305,
+        )
+        .with_counter_nonces()
+        .with_padding(Padding::Mtu(1420));
+        let message = b"raw ip packet";
+        let mut buffer = vec![0u8; 1420];
+        let start = engine.prefix_len();
+        buffer[start..start + message.len()].copy_from_slice(message);
+
+        let len = engine
+            .encrypt_in_place(&mut buffer, message.len(), b"")
+            .unwrap();
+        assert_eq!(len, 1420);
+        assert_eq!(engine.decrypt_in_place(&mut buffer, b"").unwrap(), message);
+    }
+
+    #[test]
+    fn padded_flag_is_authenticated() {
+        let engine =
+            CryptoEngine::new(&SecretKey::from([0u8; KEY_LEN])).with_padding(Padding::Multiple(16));
+        let mut record = engine.encrypt_bytes(b"data", b"").unwrap();
+        record[7] &= !(format::FLAG_PADDED as u8);
+        assert_eq!(
+            engine.decrypt_bytes(&record, b""),
+            Err(CryptoError::AuthenticationFailed)
+        );
+    }
+
+    #[test]
+    fn compression_is_off_by_default() {
+        let engine = CryptoEngine::new(&SecretKey::from([0u8; KEY_LEN]));
+        let record = engine.encrypt_bytes(&[0u8; 1000], b"").unwrap();
+        assert_eq!(record.len(), engine.record_len(1000));
+        assert_eq!(Header::parse(&record).unwrap().flags, 0);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn compressed_records_round_trip() {
+        let key = SecretKey::from([0u8; KEY_LEN]);
+        let sender = CryptoEngine::new(&key)
+            .with_compression(Compression::Lz4)
+            .with_padding(Padding::Multiple(16));
+        let receiver = CryptoEngine::new(&key);
+        let message = b"level=info msg=\"synced\" ".repeat(40);
+
+        let record = sender.encrypt_bytes(&message, b"aad").unwrap();
+        assert!(record.len() < message.len() / 4);
+        let flags = Header::parse(&record).unwrap().flags;
+        assert_eq!(flags, format::FLAG_LZ4 | format::FLAG_PADDED);
+        assert_eq!(receiver.decrypt_bytes(&record, b"aad").unwrap(), message);
+
+        let small = receiver.with_decompression_limit(message.len() - 1);
+        assert_eq!(
+            small.decrypt_bytes(&record, b"aad"),
+            Err(CryptoError::DecompressedTooLarge {
+                limit: message.len() - 1
+            })
+        );
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn compressed_in_place_needs_room_for_the_message() {
+        let key = SecretKey::from([0u8; KEY_LEN]);
+        let engine = CryptoEngine::new(&key).with_compression(Compression::Lz4);
+        let message = [b'x'; 500];
+        let mut record = engine.encrypt_bytes(&message, b"").unwrap();
+        assert_eq!(
+            engine.decrypt_in_place(&mut record, b""),
+            Err(CryptoError::BufferTooSmall {
+                needed: engine.prefix_len() + message.len()
+            })
+        );
+
+        // Padding to the MTU leaves room to decompress into.
+        let engine = engine.with_padding(Padding::Mtu(1400));
+        let mut record = engine.encrypt_bytes(&message, b"").unwrap();
+        assert_eq!(record.len(), 1400);
+        assert_eq!(engine.decrypt_in_place(&mut record, b"").unwrap(), message);
+    }
+
+    #[test]
+    fn in_place_round_trip() {
+        let engine = CryptoEngine::new(&SecretKey::from([0u8; KEY_LEN])).with_counter_nonces();
+        let message = b"raw ip packet";
+        let mut buffer = [0u8; 128];
+        let start = engine.prefix_len();
+        buffer[start..start + message.len()].copy_from_slice(message);
+
+        let len = engine
+            .encrypt_in_place(&mut buffer, message.len(), b"aad")
+            .unwrap();
+        assert_eq!(len, engine.record_len(message.len()));
+        assert_eq!(
+            engine.decrypt_bytes(&buffer[..len], b"aad").unwrap(),
+            message
+        );
+
+        let plaintext = engine.decrypt_in_place(&mut buffer[..len], b"aad").unwrap();
+        assert_eq!(plaintext, message);
+    }
+
+    #[test]
+    fn in_place_rejects_small_buffer() {
+        let engine = CryptoEngine::new(&SecretKey::from([0u8; KEY_LEN]));
+        let mut buffer = [0u8; 40];
+        assert_eq!(
+            engine.encrypt_in_place(&mut buffer, 4, b""),
+            Err(CryptoError::BufferTooSmall {
+                needed: engine.record_len(4)
+            })
+        );
+    }
+
+    #[test]
+    fn failed_in_place_decrypt_leaves_record_intact() {
+        let engine = CryptoEngine::new(&SecretKey::from([0u8; KEY_LEN]));
+        let mut record = engine.encrypt_bytes(b"payload", b"aad").unwrap();
+        let original = record.clone();
+        assert_eq!(
+            engine.decrypt_in_place(&mut record, b"wrong").map(|_| ()),
+            Err(CryptoError::AuthenticationFailed)
+        );
+        assert_eq!(record, original);
+    }
+
+    #[test]
+    fn long_aad_round_trips() {
+        let engine = CryptoEngine::new(&SecretKey::from([0u8; KEY_LEN]));
+        let aad = vec![0xabu8; INLINE_AAD_LEN * 2];
+        let record = engine.encrypt_bytes(b"payload", &aad).unwrap();
+        assert_eq!(engine.decrypt_bytes(&record, &aad).unwrap(), b"payload");
+    }
+
+    #[test]
+    fn encrypt_uses_fresh_nonces() {
+        let engine = CryptoEngine::new(&SecretKey::from([0u8; KEY_LEN]));
+        let a = engine.encrypt_bytes(b"payload", b"aad").unwrap();
+        let b = engine.encrypt_bytes(b"payload", b"aad").unwrap();
+        assert_ne!(
+            a[HEADER_LEN..HEADER_LEN + NONCE_LEN],
+            b[HEADER_LEN..HEADER_LEN + NONCE_LEN]
+        );
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn counter_nonces_carry_sequence_numbers() {
+        let engine = CryptoEngine::new(&SecretKey::from([0u8; KEY_LEN])).with_counter_nonces();
+        for expected in 0..3 {
+            let record = engine.encrypt_bytes(b"payload", b"aad").unwrap();
+            assert_eq!(format::sequence_number(&record), Ok(Some(expected)));
+            assert_eq!(engine.decrypt_bytes(&record, b"aad").unwrap(), b"payload");
+        }
+
+        let random = CryptoEngine::new(&SecretKey::from([0u8; KEY_LEN]));
+        let record = random.encrypt_bytes(b"payload", b"aad").unwrap();
+        assert_eq!(format::sequence_number(&record), Ok(None));
+    }
+
+    #[test]
+    fn counter_engine_refuses_to_wrap() {
+        let engine = CryptoEngine::new(&SecretKey::from([0u8; KEY_LEN]))
+            .with_nonce_counter(CounterNonce::with_prefix([0u8; 16], u64::MAX - 1));
+        assert!(engine.encrypt_bytes(b"payload", b"").is_ok());
+        assert_eq!(
+            engine.encrypt_bytes(b"payload", b""),
+            Err(CryptoError::NonceExhausted)
+        );
+    }
+
+    #[test]
+    fn replay_protection_rejects_duplicates() {
+        let key = [0u8; KEY_LEN];
+        let sender = CryptoEngine::new(&SecretKey::from(key)).with_counter_nonces();
+        let receiver = CryptoEngine::new(&SecretKey::from(key)).with_replay_protection();
+
+        let first = sender.encrypt_bytes(b"one", b"").unwrap();
+        let second = sender.encrypt_bytes(b"two", b"").unwrap();
+        assert_eq!(receiver.decrypt_bytes(&second, b"").unwrap(), b"two");
+        assert_eq!(receiver.decrypt_bytes(&first, b"").unwrap(), b"one");
+        assert_eq!(
+            receiver.decrypt_bytes(&first, b""),
+            Err(CryptoError::Replayed { sequence: 0 })
+        );
+    }
+
+    #[test]
+    fn replay_protection_rejects_old_records() {
+        let key = [0u8; KEY_LEN];
+        let sender = CryptoEngine::new(&SecretKey::from(key)).with_counter_nonces();
+        let receiver = CryptoEngine::new(&SecretKey::from(key)).with_replay_protection();
+
+        let old = sender.encrypt_bytes(b"old", b"").unwrap();
+        let late = CryptoEngine::new(&SecretKey::from(key))
+            .with_nonce_counter(CounterNonce::with_prefix([1u8; 16], crate::WINDOW_SIZE + 1));
+        receiver
+            .decrypt_bytes(&late.encrypt_bytes(b"new", b"").unwrap(), b"")
+            .unwrap();
+        assert_eq!(
+            receiver.decrypt_bytes(&old, b""),
+            Err(CryptoError::TooOld { sequence: 0 })
+        );
+    }
+
+    #[test]
+    fn replay_protection_ignores_forgeries() {
+        let key = [0u8; KEY_LEN];
+        let sender = CryptoEngine::new(&SecretKey::from(key)).with_counter_nonces();
+        let receiver = CryptoEngine::new(&SecretKey::from(key)).with_replay_protection();
+
+        let record = sender.encrypt_bytes(b"payload", b"").unwrap();
+        let mut forged = record.clone();
+        *forged.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            receiver.decrypt_bytes(&forged, b""),
+            Err(CryptoError::AuthenticationFailed)
+        );
+        // The forgery must not have burned the sequence number.
+        assert_eq!(receiver.decrypt_bytes(&record, b"").unwrap(), b"payload");
+    }
+
+    #[test]
+    fn replay_protection_requires_sequence_numbers() {
+        let key = [0u8; KEY_LEN];
+        let sender = CryptoEngine::new(&SecretKey::from(key));
+        let receiver = CryptoEngine::new(&SecretKey::from(key)).with_replay_protection();
+        let record = sender.encrypt_bytes(b"payload", b"").unwrap();
+        assert_eq!(
+            receiver.decrypt_bytes(&record, b""),
+            Err(CryptoError::MissingSequenceNumber)
+        );
+    }
+
+    #[test]
+    fn every_suite_round_trips() {
+        for suite in [
+            CipherSuite::XChaCha20Poly1305,
+            CipherSuite::ChaCha20Poly1305,
+            CipherSuite::Aes256Gcm,
+        ] {
+            let key = [4u8; KEY_LEN];
+            let sender =
+                CryptoEngine::with_suite(&SecretKey::from(key), suite).with_counter_nonces();
+            let receiver = CryptoEngine::with_suite(&SecretKey::from(key), suite);
+            let record = sender.encrypt_bytes(b"payload", b"aad").unwrap();
+            assert_eq!(Header::parse(&record).unwrap().suite, suite);
+            assert_eq!(
+                record.len(),
+                HEADER_LEN + suite.nonce_len() + b"payload".len() + TAG_LEN
+            );
+            assert_eq!(format::sequence_number(&record), Ok(Some(0)));
+            assert_eq!(receiver.decrypt_bytes(&record, b"aad").unwrap(), b"payload");
+        }
+    }
+
+    #[test]
+    fn decrypt_rejects_other_suite() {
+        let key = [4u8; KEY_LEN];
+        let record = CryptoEngine::with_suite(&SecretKey::from(key), CipherSuite::Aes256Gcm)
+            .encrypt_bytes(b"payload", b"")
+            .unwrap();
+        let engine = CryptoEngine::new(&SecretKey::from(key));
+        assert_eq!(
+            engine.decrypt_bytes(&record, b""),
+            Err(CryptoError::SuiteMismatch { suite: 3 })
+        );
+    }
+
+    #[test]
+    fn header_is_authenticated() {
+        let engine = CryptoEngine::new(&SecretKey::from([0u8; KEY_LEN]));
+        let mut record = engine.encrypt_bytes(b"payload", b"aad").unwrap();
+        // Swap in a header that still parses but differs from the sealed one.
+        record[..HEADER_LEN]
+            .copy_from_slice(&Header::new(CipherSuite::XChaCha20Poly1305, 0).encode());
+        assert!(engine.decrypt_bytes(&record, b"aad").is_ok());
+        record[8..HEADER_LEN].copy_from_slice(&7u32.to_be_bytes());
+        let engine = CryptoEngine::new(&SecretKey::from([0u8; KEY_LEN])).with_key_id(7);
+        assert_eq!(
+            engine.decrypt_bytes(&record, b"aad"),
+            Err(CryptoError::AuthenticationFailed)
+        );
+    }
+
+    #[test]
+    fn decrypt_rejects_other_key_id() {
+        let record = CryptoEngine::new(&SecretKey::from([0u8; KEY_LEN]))
+            .with_key_id(1)
+            .encrypt_bytes(b"payload", b"")
+            .unwrap();
+        let engine = CryptoEngine::new(&SecretKey::from([0u8; KEY_LEN])).with_key_id(2);
+        assert_eq!(
+            engine.decrypt_bytes(&record, b""),
+            Err(CryptoError::UnknownKeyId { key_id: 1 })
+        );
+    }
+
+    #[test]
+    fn decrypt_accepts_legacy_record() {
+        let engine = CryptoEngine::new(&SecretKey::from([3u8; KEY_LEN]));
+        let nonce = [5u8; NONCE_LEN];
+        let mut legacy = nonce.to_vec();
+        legacy.extend(engine.cipher.seal(&nonce, b"old data", b"aad").unwrap());
+        assert_eq!(engine.decrypt_bytes(&legacy, b"aad").unwrap(), b"old data");
+    }
+
+    #[test]
+    fn decrypt_accepts_legacy_record_with_magic_nonce() {
+        let engine = CryptoEngine::new(&SecretKey::from([3u8; KEY_LEN]));
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[..4].copy_from_slice(&format::MAGIC);
+        let mut legacy = nonce.to_vec();
+        legacy.extend(engine.cipher.seal(&nonce, b"old data", b"").unwrap());
+        assert_eq!(engine.decrypt_bytes(&legacy, b"").unwrap(), b"old data");
+    }
+
+    /// Encryption half of draft-irtf-cfrg-xchacha-03, Appendix A.3.1, then the
+    /// same vector as a complete record.
+    #[test]
+    fn known_answer_encrypt() {
+        let key: [u8; KEY_LEN] = core::array::from_fn(|i| 0x80 + i as u8);
+        let nonce: [u8; NONCE_LEN] = core::array::from_fn(|i| 0x40 + i as u8);
+        let aad = [
+            0x50, 0x51, 0x52, 0x53, 0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7,
+        ];
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+
+        const CIPHERTEXT: [u8; 114] = [
+            0xbd, 0x6d, 0x17, 0x9d, 0x3e, 0x83, 0xd4, 0x3b, 0x95, 0x76, 0x57, 0x94, 0x93, 0xc0,
+            0xe9, 0x39, 0x57, 0x2a, 0x17, 0x00, 0x25, 0x2b, 0xfa, 0xcc, 0xbe, 0xd2, 0x90, 0x2c,
+            0x21, 0x39, 0x6c, 0xbb, 0x73, 0x1c, 0x7f, 0x1b, 0x0b, 0x4a, 0xa6, 0x44, 0x0b, 0xf3,
+            0xa8, 0x2f, 0x4e, 0xda, 0x7e, 0x39, 0xae, 0x64, 0xc6, 0x70, 0x8c, 0x54, 0xc2, 0x16,
+            0xcb, 0x96, 0xb7, 0x2e, 0x12, 0x13, 0xb4, 0x52, 0x2f, 0x8c, 0x9b, 0xa4, 0x0d, 0xb5,
+            0xd9, 0x45, 0xb1, 0x1b, 0x69, 0xb9, 0x82, 0xc1, 0xbb, 0x9e, 0x3f, 0x3f, 0xac, 0x2b,
+            0xc3, 0x69, 0x48, 0x8f, 0x76, 0xb2, 0x38, 0x35, 0x65, 0xd3, 0xff, 0xf9, 0x21, 0xf9,
+            0x66, 0x4c, 0x97, 0x63, 0x7d, 0xa9, 0x76, 0x88, 0x12, 0xf6, 0x15, 0xc6, 0x8b, 0x13,
+            0xb5, 0x2e,
+        ];
+        const TAG: [u8; TAG_LEN] = [
+            0xc0, 0x87, 0x59, 0x24, 0xc1, 0xc7, 0x98, 0x79, 0x47, 0xde, 0xaf, 0xd8, 0x78, 0x0a,
+            0xcf, 0x49,
+        ];
+        let engine = CryptoEngine::new(&SecretKey::from(key)).with_key_id(0x0102_0304);
+        let sealed = engine.cipher.seal(&nonce, plaintext, &aad).unwrap();
+        assert_eq!(sealed, [&CIPHERTEXT[..], &TAG].concat());
+
+        // The same key and nonce as a full record: the keystream is unchanged,
+        // and the tag also covers the header.
+        let mut record = vec![0u8; engine.record_len(plaintext.len())];
+        record[HEADER_LEN..][..NONCE_LEN].copy_from_slice(&nonce);
+        record[engine.prefix_len()..][..plaintext.len()].copy_from_slice(plaintext);
+        let len = engine
+            .seal_in_place(&mut record, plaintext.len(), &aad)
+            .unwrap();
+        let header = [
+            b'V', b'P', b'N', b'E', 0x01, 0x01, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04,
+        ];
+        // Tag for AAD `header || aad`, computed outside this crate.
+        let record_tag = [
+            0x0a, 0xb9, 0xb6, 0x10, 0xf2, 0xfd, 0x21, 0x99, 0x03, 0xce, 0x5d, 0xb9, 0xe2, 0x59,
+            0x11, 0xed,
+        ];
+        assert_eq!(
+            record[..len],
+            [&header[..], &nonce, &CIPHERTEXT, &record_tag].concat()
+        );
+    }
+}
@@ -0,0 +1,171 @@
+use std::fmt;
+
+/// Errors returned by [`CryptoEngine`](crate::CryptoEngine) operations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CryptoError {
+    /// The input is too short to contain a nonce and tag (and header, if any).
+    TruncatedInput {
+        /// Length of the rejected input in bytes.
+        len: usize,
+    },
+    /// The record header is malformed.
+    InvalidHeader,
+    /// The record uses a wire format version this build does not understand.
+    UnsupportedVersion {
+        /// Version byte found in the header.
+        version: u8,
+    },
+    /// The record uses a cipher suite this build does not implement.
+    UnsupportedSuite {
+        /// Suite id found in the header.
+        suite: u8,
+    },
+    /// The record was sealed with a different cipher suite than the engine's.
+    SuiteMismatch {
+        /// Suite id found in the header.
+        suite: u8,
+    },
+    /// The record was sealed with a key this engine does not hold.
+    UnknownKeyId {
+        /// Key id found in the header.
+        key_id: u32,
+    },
+    /// Key derivation rejected its parameters (e.g. salt too short).
+    KeyDerivationFailed,
+    /// A keyring has no active key to encrypt with.
+    NoActiveKey,
+    /// The operation would retire or remove the keyring's active key.
+    ActiveKeyInUse {
+        /// Id of the active key.
+        key_id: u32,
+    },
+    /// The nonce counter would wrap; the key must be replaced.
+    NonceExhausted,
+    /// The record's sequence number has already been accepted.
+    Replayed {
+        /// Duplicate sequence number.
+        sequence: u64,
+    },
+    /// The record's sequence number is behind the replay window.
+    TooOld {
+        /// Rejected sequence number.
+        sequence: u64,
+    },
+    /// Replay protection is enabled but the record carries no sequence number.
+    MissingSequenceNumber,
+    /// A caller-provided buffer cannot hold the record.
+    BufferTooSmall {
+        /// Number of bytes the buffer needs.
+        needed: usize,
+    },
+    /// A handshake message was written or read out of turn, or the handshake
+    /// was finished before completing.
+    HandshakeOutOfOrder,
+    /// A peer's public key is a low-order point that yields a known secret.
+    InvalidPublicKey,
+    /// An encrypted stream ended before its final chunk.
+    TruncatedStream,
+    /// A record flagged as padded authenticated but its padding is
+    /// malformed, which only a faulty sender can cause.
+    InvalidPadding,
+    /// The record is compressed with a codec this build was compiled
+    /// without.
+    UnsupportedCompression,
+    /// The record's compressed payload is malformed.
+    DecompressionFailed,
+    /// The record's payload decompresses to more than the engine allows.
+    DecompressedTooLarge {
+        /// The engine's decompression limit in bytes.
+        limit: usize,
+    },
+    /// The data is not a well-formed key file.
+    InvalidKeyFile,
+    /// The key file is protected and no passphrase was given.
+    PassphraseRequired,
+    /// The AEAD cipher refused to encrypt the payload.
+    EncryptionFailed,
+    /// The ciphertext, tag or AAD did not authenticate.
+    AuthenticationFailed,
+    /// Decryption succeeded but the plaintext is not valid UTF-8.
+    InvalidUtf8,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::TruncatedInput { len } => {
+                write!(f, "Invalid data: record too short ({} bytes)", len)
+            }
+            CryptoError::InvalidHeader => write!(f, "Invalid data: malformed record header"),
+            CryptoError::UnsupportedVersion { version } => {
+                write!(f, "Unsupported record format version {}", version)
+            }
+            CryptoError::UnsupportedSuite { suite } => {
+                write!(f, "Unsupported cipher suite id {}", suite)
+            }
+            CryptoError::SuiteMismatch { suite } => {
+                write!(
+                    f,
+                    "Record sealed with cipher suite id {}, engine uses another",
+                    suite
+                )
+            }
+            CryptoError::UnknownKeyId { key_id } => write!(f, "Unknown key id {}", key_id),
+            CryptoError::KeyDerivationFailed => write!(f, "Key derivation failed"),
+            CryptoError::NoActiveKey => write!(f, "Keyring has no active key"),
+            CryptoError::ActiveKeyInUse { key_id } => {
+                write!(f, "Key id {} is the active key", key_id)
+            }
+            CryptoError::NonceExhausted => write!(f, "Nonce counter exhausted; rekey required"),
+            CryptoError::Replayed { sequence } => {
+                write!(f, "Replayed record: sequence {} already received", sequence)
+            }
+            CryptoError::TooOld { sequence } => {
+                write!(
+                    f,
+                    "Record sequence {} is older than the replay window",
+                    sequence
+                )
+            }
+            CryptoError::MissingSequenceNumber => {
+                write!(f, "Record carries no sequence number for replay protection")
+            }
+            CryptoError::BufferTooSmall { needed } => {
+                write!(f, "Buffer too small: {} bytes needed", needed)
+            }
+            CryptoError::HandshakeOutOfOrder => {
+                write!(f, "Handshake message out of order or handshake incomplete")
+            }
+            CryptoError::InvalidPublicKey => write!(f, "Peer public key is invalid"),
+            CryptoError::TruncatedStream => {
+                write!(f, "Encrypted stream ended before its final chunk")
+            }
+            CryptoError::InvalidPadding => write!(f, "Invalid data: malformed padding"),
+            CryptoError::UnsupportedCompression => {
+                write!(f, "Record compressed with an unsupported codec")
+            }
+            CryptoError::DecompressionFailed => {
+                write!(f, "Invalid data: malformed compressed payload")
+            }
+            CryptoError::DecompressedTooLarge { limit } => {
+                write!(f, "Decompressed payload exceeds the {} byte limit", limit)
+            }
+            CryptoError::InvalidKeyFile => write!(f, "Invalid key file"),
+            CryptoError::PassphraseRequired => {
+                write!(
+                    f,
+                    "Key file is passphrase-protected; a passphrase is required"
+                )
+            }
+            CryptoError::EncryptionFailed => write!(f, "Encryption failed"),
+            CryptoError::AuthenticationFailed => {
+                write!(f, "Decryption failed: invalid ciphertext or wrong AAD")
+            }
+            CryptoError::InvalidUtf8 => {
+                write!(f, "Decryption succeeded but result is not valid UTF-8")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
@@ -0,0 +1,232 @@
+//! Wire format for encrypted records.
+//!
+//! Every record produced by [`CryptoEngine`](crate::CryptoEngine) starts with
+//! a fixed 12-byte header:
+//!
+//! ```text
+//! offset  size  field
+//!      0     4  magic "VPNE"
+//!      4     1  format version
+//!      5     1  cipher suite id
+//!      6     2  flags (big endian)
+//!      8     4  key id (big endian)
+//! ```
+//!
+//! The header is followed by the nonce (its length depends on the suite, see
+//! [`CipherSuite::nonce_len`]) and the AEAD ciphertext. It is
+//! authenticated as a prefix of the AAD, so it cannot be altered without the
+//! record failing to decrypt.
+//!
+//! Records written before the header existed are bare
+//! `[24-byte nonce][ciphertext + tag]`; they are still accepted on decrypt.
+
+use crate::error::CryptoError;
+use crate::nonce;
+
+/// Magic bytes identifying a headered record.
+pub const MAGIC: [u8; 4] = *b"VPNE";
+
+/// Current wire format version.
+pub const VERSION: u8 = 1;
+
+/// Size of the encoded header in bytes.
+pub const HEADER_LEN: usize = 12;
+
+/// Flag bit: the nonce is a [`CounterNonce`](crate::nonce::CounterNonce),
+/// so its last 8 bytes are a big-endian sequence number.
+pub const FLAG_COUNTER_NONCE: u16 = 0x0001;
+
+/// Flag bit: the plaintext ends in [`padding`](crate::padding) that is
+/// stripped on decrypt.
+pub const FLAG_PADDED: u16 = 0x0002;
+
+/// Flag bit: the plaintext is LZ4-compressed; see
+/// [`compression`](crate::compression).
+pub const FLAG_LZ4: u16 = 0x0004;
+
+/// Flag bit: the plaintext is zstd-compressed; see
+/// [`compression`](crate::compression).
+pub const FLAG_ZSTD: u16 = 0x0008;
+
+/// All flag bits this build understands. Records with other bits set are
+/// rejected.
+pub const KNOWN_FLAGS: u16 = FLAG_COUNTER_NONCE | FLAG_PADDED | FLAG_LZ4 | FLAG_ZSTD;
+
+/// AEAD algorithm used to protect a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CipherSuite {
+    /// XChaCha20-Poly1305 with a 24-byte nonce.
+    XChaCha20Poly1305 = 1,
+    /// ChaCha20-Poly1305 (RFC 8439) with a 12-byte nonce.
+    ChaCha20Poly1305 = 2,
+    /// AES-256-GCM with a 12-byte nonce.
+    Aes256Gcm = 3,
+}
+
+impl CipherSuite {
+    /// Returns the suite for a wire id.
+    pub fn from_id(id: u8) -> Result<Self, CryptoError> {
+        match id {
+            1 => Ok(CipherSuite::XChaCha20Poly1305),
+            2 => Ok(CipherSuite::ChaCha20Poly1305),
+            3 => Ok(CipherSuite::Aes256Gcm),
+            _ => Err(CryptoError::UnsupportedSuite { suite: id }),
+        }
+    }
+
+    /// Returns the wire id of the suite.
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    /// Returns the nonce length the suite uses, in bytes.
+    pub fn nonce_len(self) -> usize {
+        match self {
+            CipherSuite::XChaCha20Poly1305 => 24,
+            CipherSuite::ChaCha20Poly1305 | CipherSuite::Aes256Gcm => 12,
+        }
+    }
+}
+
+/// Decoded record header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// Wire format version.
+    pub version: u8,
+    /// AEAD algorithm protecting the payload.
+    pub suite: CipherSuite,
+    /// Flag bits, see [`FLAG_COUNTER_NONCE`].
+    pub flags: u16,
+    /// Identifier of the key the record was sealed with.
+    pub key_id: u32,
+}
+
+impl Header {
+    /// Creates a header for the current format version.
+    pub fn new(suite: CipherSuite, key_id: u32) -> Self {
+        Self {
+            version: VERSION,
+            suite,
+            flags: 0,
+            key_id,
+        }
+    }
+
+    /// Serializes the header into its wire representation.
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut out = [0u8; HEADER_LEN];
+        out[..4].copy_from_slice(&MAGIC);
+        out[4] = self.version;
+        out[5] = self.suite.id();
+        out[6..8].copy_from_slice(&self.flags.to_be_bytes());
+        out[8..12].copy_from_slice(&self.key_id.to_be_bytes());
+        out
+    }
+
+    /// Parses the header at the start of `data`.
+    pub fn parse(data: &[u8]) -> Result<Self, CryptoError> {
+        if data.len() < HEADER_LEN {
+            return Err(CryptoError::TruncatedInput { len: data.len() });
+        }
+        if data[..4] != MAGIC {
+            return Err(CryptoError::InvalidHeader);
+        }
+
+        let version = data[4];
+        if version != VERSION {
+            return Err(CryptoError::UnsupportedVersion { version });
+        }
+        let suite = CipherSuite::from_id(data[5])?;
+        let flags = u16::from_be_bytes([data[6], data[7]]);
+        // At most one compression codec
+        if flags & !KNOWN_FLAGS != 0 || flags & (FLAG_LZ4 | FLAG_ZSTD) == FLAG_LZ4 | FLAG_ZSTD {
+            return Err(CryptoError::InvalidHeader);
+        }
+        let key_id = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
+
+        Ok(Self {
+            version,
+            suite,
+            flags,
+            key_id,
+        })
+    }
+}
+
+/// Returns the sequence number carried in a record's counter nonce, or
+/// `None` if the record uses random nonces.
+pub fn sequence_number(data: &[u8]) -> Result<Option<u64>, CryptoError> {
+    let header = Header::parse(data)?;
+    if header.flags & FLAG_COUNTER_NONCE == 0 {
+        return Ok(None);
+    }
+    let nonce = data
+        .get(HEADER_LEN..HEADER_LEN + header.suite.nonce_len())
+        .ok_or(CryptoError::TruncatedInput { len: data.len() })?;
+    Ok(Some(nonce::counter_of(nonce)))
+}
+
+/// Returns `true` if `data` looks like a headered record rather than a
+/// legacy headerless one.
+pub fn has_header(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trip() {
+        let header = Header::new(CipherSuite::XChaCha20Poly1305, 0xdead_beef);
+        let bytes = header.encode();
+        assert_eq!(&bytes[..4], b"VPNE");
+        assert_eq!(Header::parse(&bytes).unwrap(), header);
+    }
+
+    #[test]
+    fn parse_rejects_unknown_version() {
+        let mut bytes = Header::new(CipherSuite::XChaCha20Poly1305, 1).encode();
+        bytes[4] = 9;
+        assert_eq!(
+            Header::parse(&bytes),
+            Err(CryptoError::UnsupportedVersion { version: 9 })
+        );
+    }
+
+    #[test]
+    fn parse_rejects_unknown_suite() {
+        let mut bytes = Header::new(CipherSuite::XChaCha20Poly1305, 1).encode();
+        bytes[5] = 0xee;
+        assert_eq!(
+            Header::parse(&bytes),
+            Err(CryptoError::UnsupportedSuite { suite: 0xee })
+        );
+    }
+
+    #[test]
+    fn parse_rejects_reserved_flags() {
+        let mut bytes = Header::new(CipherSuite::XChaCha20Poly1305, 1).encode();
+        bytes[7] = 0x80;
+        assert_eq!(Header::parse(&bytes), Err(CryptoError::InvalidHeader));
+    }
+
+    #[test]
+    fn parse_rejects_two_codecs() {
+        let mut header = Header::new(CipherSuite::XChaCha20Poly1305, 1);
+        header.flags = FLAG_LZ4 | FLAG_ZSTD;
+        assert_eq!(
+            Header::parse(&header.encode()),
+            Err(CryptoError::InvalidHeader)
+        );
+    }
+
+    #[test]
+    fn parse_rejects_short_input() {
+        assert_eq!(
+            Header::parse(b"VPNE"),
+            Err(CryptoError::TruncatedInput { len: 4 })
+        );
+    }
+}
@@ -0,0 +1,783 @@
+//! Authenticated key exchange between two peers.
+//!
+//! This is the Noise Protocol Framework (revision 34) instantiated as
+//! `Noise_XX_25519_ChaChaPoly_SHA256` and `Noise_IK_25519_ChaChaPoly_SHA256`:
+//! X25519 for Diffie-Hellman, ChaCha20-Poly1305 for the handshake payloads
+//! and SHA-256 / HKDF for hashing and key derivation.
+//!
+//! ```text
+//! XX:                          IK:
+//!   -> e                         <- s            (known in advance)
+//!   <- e, ee, s, es              ...
+//!   -> s, se                     -> e, es, s, ss
+//!                                <- e, ee, se
+//! ```
+//!
+//! Use [`Pattern::XX`] when neither side knows the other's static key in
+//! advance (the keys are exchanged encrypted and should be checked against
+//! an allow list afterwards), and [`Pattern::IK`] when the initiator already
+//! knows the responder's key, which saves a round trip.
+//!
+//! Both peers drive their [`Handshake`] by passing each message written by
+//! one side to [`read_message`](Handshake::read_message) on the other. Once
+//! the pattern completes, [`finish`](Handshake::finish) yields a pair of
+//! [`CryptoEngine`]s keyed from fresh ephemeral secrets, so recording the
+//! traffic and later stealing a static key does not reveal past sessions.
+//!
+//! ```
+//! use vpn_encrypt::handshake::{Handshake, StaticKeypair};
+//!
+//! let alice = StaticKeypair::generate();
+//! let bob = StaticKeypair::generate();
+//! let mut initiator = Handshake::xx_initiator(&alice);
+//! let mut responder = Handshake::xx_responder(&bob);
+//!
+//! let m1 = initiator.write_message(b"")?;
+//! responder.read_message(&m1)?;
+//! let m2 = responder.write_message(b"")?;
+//! initiator.read_message(&m2)?;
+//! let m3 = initiator.write_message(b"")?;
+//! responder.read_message(&m3)?;
+//!
+//! let alice_keys = initiator.finish()?;
+//! let bob_keys = responder.finish()?;
+//! assert_eq!(alice_keys.remote_static, bob.public_key());
+//!
+//! let record = alice_keys.send.encrypt_bytes(b"ping", b"")?;
+//! assert_eq!(bob_keys.receive.decrypt_bytes(&record, b"")?, b"ping");
+//! # Ok::<(), vpn_encrypt::CryptoError>(())
+//! ```
+
+use std::fmt;
+
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, ReusableSecret, SharedSecret, StaticSecret};
+use zeroize::Zeroize;
+
+use crate::engine::{CryptoEngine, TAG_LEN};
+use crate::error::CryptoError;
+use crate::format::CipherSuite;
+use crate::kdf;
+use crate::key::SecretKey;
+use crate::session::Role;
+use crate::suite::AeadSuite;
+
+/// Length of an X25519 public key in bytes.
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+const HASH_LEN: usize = 32;
+
+/// HKDF info label for [`TransportKeys::rekey_secret`].
+const REKEY_EXPORT_LABEL: &[u8] = b"vpn-encrypt handshake rekey secret";
+
+/// A handshake pattern, i.e. which static keys are exchanged and when.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    /// Both static keys are transmitted during the handshake (3 messages).
+    XX,
+    /// The initiator knows the responder's static key in advance (2 messages).
+    IK,
+}
+
+#[derive(Clone, Copy)]
+enum Token {
+    E,
+    S,
+    EE,
+    ES,
+    SE,
+    SS,
+}
+
+impl Pattern {
+    fn protocol_name(self) -> &'static [u8] {
+        match self {
+            Pattern::XX => b"Noise_XX_25519_ChaChaPoly_SHA256",
+            Pattern::IK => b"Noise_IK_25519_ChaChaPoly_SHA256",
+        }
+    }
+
+    fn messages(self) -> &'static [&'static [Token]] {
+        use Token::*;
+        match self {
+            Pattern::XX => &[&[E], &[E, EE, S, ES], &[S, SE]],
+            Pattern::IK => &[&[E, ES, S, SS], &[E, EE, SE]],
+        }
+    }
+}
+
+/// A long-term X25519 identity key.
+///
+/// The private half is wiped on drop and never printed by `Debug`.
+#[derive(Clone)]
+pub struct StaticKeypair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl StaticKeypair {
+    /// Generates a fresh keypair from the operating system RNG.
+    pub fn generate() -> Self {
+        Self::from(StaticSecret::random_from_rng(OsRng))
+    }
+
+    /// Rebuilds a keypair from stored private key bytes.
+    pub fn from_secret(secret: &SecretKey) -> Self {
+        Self::from(StaticSecret::from(*secret.as_bytes()))
+    }
+
+    /// Returns the public key to hand out to peers.
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_LEN] {
+        self.public.to_bytes()
+    }
+
+    /// Returns the private key bytes for storage.
+    pub fn secret_key(&self) -> SecretKey {
+        SecretKey::from(self.secret.to_bytes())
+    }
+}
+
+impl From<StaticSecret> for StaticKeypair {
+    fn from(secret: StaticSecret) -> Self {
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+}
+
+impl fmt::Debug for StaticKeypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StaticKeypair")
+            .field("public", &self.public.as_bytes())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Keys produced by a completed handshake.
+///
+/// Wrap them in a [`Session`](crate::session::Session) to also bind each
+/// direction into the records' AAD.
+#[derive(Debug)]
+pub struct TransportKeys {
+    /// Whether this side initiated the handshake.
+    pub role: Role,
+    /// Engine for records sent to the peer. Uses counter nonces, so the
+    /// peer's `receive` engine can reject replays.
+    pub send: CryptoEngine,
+    /// Engine for records received from the peer, with replay protection.
+    pub receive: CryptoEngine,
+    /// The peer's static public key, as proven during the handshake.
+    pub remote_static: [u8; PUBLIC_KEY_LEN],
+    /// Hash of the whole handshake transcript. Identical on both sides, so
+    /// it can serve as a session id or be bound into channel AAD.
+    pub handshake_hash: [u8; HASH_LEN],
+    /// Secret shared by both sides for keys beyond `send` and `receive`,
+    /// such as a [`RekeyingSession`](crate::rekey::RekeyingSession) ratchet.
+    /// Derived from the final chaining key under its own HKDF label, so it
+    /// reveals nothing about the transport keys.
+    pub rekey_secret: SecretKey,
+}
+
+/// Noise `CipherState`: a handshake key plus its message counter.
+struct CipherState {
+    cipher: Option<Box<dyn AeadSuite>>,
+    nonce: u64,
+}
+
+impl CipherState {
+    fn set_key(&mut self, key: [u8; 32]) {
+        self.cipher = Some(CipherSuite::ChaCha20Poly1305.cipher(&SecretKey::from(key)));
+        self.nonce = 0;
+    }
+
+    /// Encrypts `plaintext`, or passes it through before a key is set.
+    fn encrypt_with_ad(&mut self, ad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let Some(cipher) = &self.cipher else {
+            return Ok(plaintext.to_vec());
+        };
+        let nonce = noise_nonce(&mut self.nonce)?;
+        cipher.seal(&nonce, plaintext, ad)
+    }
+
+    /// Decrypts `ciphertext`, or passes it through before a key is set.
+    fn decrypt_with_ad(&mut self, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let Some(cipher) = &self.cipher else {
+            return Ok(ciphertext.to_vec());
+        };
+        let nonce = noise_nonce(&mut self.nonce)?;
+        cipher.open(&nonce, ciphertext, ad)
+    }
+}
+
+/// Returns the Noise nonce for `counter` (32 zero bits followed by the
+/// little-endian counter) and advances it.
+fn noise_nonce(counter: &mut u64) -> Result<[u8; 12], CryptoError> {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    *counter = counter.checked_add(1).ok_or(CryptoError::NonceExhausted)?;
+    Ok(nonce)
+}
+
+/// Noise `SymmetricState`: the chaining key and transcript hash.
+struct SymmetricState {
+    chaining_key: [u8; HASH_LEN],
+    hash: [u8; HASH_LEN],
+    cipher: CipherState,
+}
+
+impl SymmetricState {
+    fn new(protocol_name: &[u8]) -> Self {
+        // Both protocol names are exactly HASH_LEN bytes, so they are used
+        // as the initial hash directly rather than hashed.
+        let hash: [u8; HASH_LEN] = protocol_name.try_into().expect("name is HASH_LEN bytes");
+        Self {
+            chaining_key: hash,
+            hash,
+            cipher: CipherState {
+                cipher: None,
+                nonce: 0,
+            },
+        }
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.hash = Sha256::new()
+            .chain_update(self.hash)
+            .chain_update(data)
+            .finalize()
+            .into();
+    }
+
+    fn mix_key(&mut self, input: &[u8]) {
+        let (chaining_key, mut key) = hkdf2(&self.chaining_key, input);
+        self.chaining_key = chaining_key;
+        self.cipher.set_key(key);
+        key.zeroize();
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let ciphertext = self.cipher.encrypt_with_ad(&self.hash, plaintext)?;
+        self.mix_hash(&ciphertext);
+        Ok(ciphertext)
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let plaintext = self.cipher.decrypt_with_ad(&self.hash, ciphertext)?;
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    /// Length of `len` plaintext bytes once encrypted under the current state.
+    fn sealed_len(&self, len: usize) -> usize {
+        match self.cipher.cipher {
+            Some(_) => len + TAG_LEN,
+            None => len,
+        }
+    }
+
+    fn split(&self) -> ([u8; 32], [u8; 32]) {
+        hkdf2(&self.chaining_key, &[])
+    }
+
+    /// An extra secret from the chaining key, independent of [`split`]'s
+    /// output because it uses a non-empty HKDF info label.
+    fn export(&self) -> SecretKey {
+        let mut secret = SecretKey::from([0u8; 32]);
+        kdf::hkdf_sha256(
+            Some(&self.chaining_key),
+            &[],
+            REKEY_EXPORT_LABEL,
+            secret.as_bytes_mut(),
+        )
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+        secret
+    }
+}
+
+impl Drop for SymmetricState {
+    fn drop(&mut self) {
+        self.chaining_key.zeroize();
+    }
+}
+
+/// Noise's two-output HKDF, which is HKDF-SHA256 with the chaining key as
+/// salt and empty info.
+fn hkdf2(chaining_key: &[u8; HASH_LEN], input: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut okm = [0u8; 64];
+    kdf::hkdf_sha256(Some(chaining_key), input, &[], &mut okm)
+        .expect("64 bytes is a valid HKDF-SHA256 output length");
+    let mut first = [0u8; 32];
+    let mut second = [0u8; 32];
+    first.copy_from_slice(&okm[..32]);
+    second.copy_from_slice(&okm[32..]);
+    okm.zeroize();
+    (first, second)
+}
+
+/// Rejects low-order peer keys, which would make the shared secret known.
+fn check_contributory(shared: SharedSecret) -> Result<SharedSecret, CryptoError> {
+    if shared.was_contributory() {
+        Ok(shared)
+    } else {
+        Err(CryptoError::InvalidPublicKey)
+    }
+}
+
+/// One side of an in-progress handshake.
+///
+/// Messages must be written and read strictly in turn. If any call fails
+/// the handshake must be abandoned and restarted from scratch.
+pub struct Handshake {
+    pattern: Pattern,
+    initiator: bool,
+    symmetric: SymmetricState,
+    local_static: StaticKeypair,
+    local_ephemeral: Option<(ReusableSecret, PublicKey)>,
+    remote_static: Option<PublicKey>,
+    remote_ephemeral: Option<PublicKey>,
+    message: usize,
+}
+
+impl Handshake {
+    fn new(
+        pattern: Pattern,
+        initiator: bool,
+        local_static: &StaticKeypair,
+        responder_static: Option<PublicKey>,
+    ) -> Self {
+        let mut symmetric = SymmetricState::new(pattern.protocol_name());
+        // Empty prologue.
+        symmetric.mix_hash(&[]);
+        let mut remote_static = None;
+        if pattern == Pattern::IK {
+            // Pre-message `<- s`: the responder's static key is hashed first.
+            let responder_static = responder_static.unwrap_or(local_static.public);
+            symmetric.mix_hash(responder_static.as_bytes());
+            if initiator {
+                remote_static = Some(responder_static);
+            }
+        }
+        Self {
+            pattern,
+            initiator,
+            symmetric,
+            local_static: local_static.clone(),
+            local_ephemeral: None,
+            remote_static,
+            remote_ephemeral: None,
+            message: 0,
+        }
+    }
+
+    /// Starts an XX handshake as the initiator.
+    pub fn xx_initiator(local: &StaticKeypair) -> Self {
+        Self::new(Pattern::XX, true, local, None)
+    }
+
+    /// Starts an XX handshake as the responder.
+    pub fn xx_responder(local: &StaticKeypair) -> Self {
+        Self::new(Pattern::XX, false, local, None)
+    }
+
+    /// Starts an IK handshake as the initiator, towards a responder whose
+    /// static public key is already known.
+    pub fn ik_initiator(local: &StaticKeypair, responder: &[u8; PUBLIC_KEY_LEN]) -> Self {
+        Self::new(Pattern::IK, true, local, Some(PublicKey::from(*responder)))
+    }
+
+    /// Starts an IK handshake as the responder.
+    pub fn ik_responder(local: &StaticKeypair) -> Self {
+        Self::new(Pattern::IK, false, local, None)
+    }
+
+    /// Returns the handshake pattern.
+    pub fn pattern(&self) -> Pattern {
+        self.pattern
+    }
+
+    /// Returns `true` once every handshake message has been exchanged.
+    pub fn is_finished(&self) -> bool {
+        self.message == self.pattern.messages().len()
+    }
+
+    /// Returns `true` if the next step is [`write_message`](Self::write_message).
+    pub fn is_my_turn(&self) -> bool {
+        !self.is_finished() && self.message.is_multiple_of(2) == self.initiator
+    }
+
+    /// Returns the peer's static public key once it has been received.
+    pub fn remote_static(&self) -> Option<[u8; PUBLIC_KEY_LEN]> {
+        self.remote_static.map(|key| key.to_bytes())
+    }
+
+    /// Writes the next handshake message, carrying `payload`.
+    ///
+    /// The payload is encrypted once a key has been mixed in, which is from
+    /// the second XX message and the first IK message onward.
+    pub fn write_message(&mut self, payload: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if !self.is_my_turn() {
+            return Err(CryptoError::HandshakeOutOfOrder);
+        }
+        let mut out = Vec::new();
+        for &token in self.pattern.messages()[self.message] {
+            match token {
+                Token::E => {
+                    let secret = ReusableSecret::random_from_rng(OsRng);
+                    let public = PublicKey::from(&secret);
+                    out.extend_from_slice(public.as_bytes());
+                    self.symmetric.mix_hash(public.as_bytes());
+                    self.local_ephemeral = Some((secret, public));
+                }
+                Token::S => {
+                    let public = self.local_static.public;
+                    out.extend(self.symmetric.encrypt_and_hash(public.as_bytes())?);
+                }
+                _ => self.mix_dh(token)?,
+            }
+        }
+        out.extend(self.symmetric.encrypt_and_hash(payload)?);
+        self.message += 1;
+        Ok(out)
+    }
+
+    /// Reads the peer's next handshake message and returns its payload.
+    pub fn read_message(&mut self, message: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if self.is_finished() || self.is_my_turn() {
+            return Err(CryptoError::HandshakeOutOfOrder);
+        }
+        let truncated = CryptoError::TruncatedInput { len: message.len() };
+        let mut rest = message;
+        for &token in self.pattern.messages()[self.message] {
+            match token {
+                Token::E => {
+                    let (key, tail) = rest
+                        .split_first_chunk::<PUBLIC_KEY_LEN>()
+                        .ok_or_else(|| truncated.clone())?;
+                    self.symmetric.mix_hash(key);
+                    self.remote_ephemeral = Some(PublicKey::from(*key));
+                    rest = tail;
+                }
+                Token::S => {
+                    let len = self.symmetric.sealed_len(PUBLIC_KEY_LEN);
+                    if rest.len() < len {
+                        return Err(truncated);
+                    }
+                    let (sealed, tail) = rest.split_at(len);
+                    let key: [u8; PUBLIC_KEY_LEN] = self
+                        .symmetric
+                        .decrypt_and_hash(sealed)?
+                        .try_into()
+                        .expect("decrypted key is PUBLIC_KEY_LEN bytes");
+                    self.remote_static = Some(PublicKey::from(key));
+                    rest = tail;
+                }
+                _ => self.mix_dh(token)?,
+            }
+        }
+        if rest.len() < self.symmetric.sealed_len(0) {
+            return Err(truncated);
+        }
+        let payload = self.symmetric.decrypt_and_hash(rest)?;
+        self.message += 1;
+        Ok(payload)
+    }
+
+    /// Completes the handshake and derives the transport engines.
+    pub fn finish(self) -> Result<TransportKeys, CryptoError> {
+        if !self.is_finished() {
+            return Err(CryptoError::HandshakeOutOfOrder);
+        }
+        let (mut initiator_key, mut responder_key) = self.symmetric.split();
+        let (send, receive) = match self.initiator {
+            true => (&initiator_key, &responder_key),
+            false => (&responder_key, &initiator_key),
+        };
+        let keys = TransportKeys {
+            role: match self.initiator {
+                true => Role::Initiator,
+                false => Role::Responder,
+            },
+            send: CryptoEngine::new(&SecretKey::from(*send)).with_counter_nonces(),
+            receive: CryptoEngine::new(&SecretKey::from(*receive)).with_replay_protection(),
+            remote_static: self
+                .remote_static
+                .expect("every pattern transmits or pre-shares the remote static key")
+                .to_bytes(),
+            handshake_hash: self.symmetric.hash,
+            rekey_secret: self.symmetric.export(),
+        };
+        initiator_key.zeroize();
+        responder_key.zeroize();
+        Ok(keys)
+    }
+
+    /// Performs the Diffie-Hellman named by `token` and mixes in the result.
+    fn mix_dh(&mut self, token: Token) -> Result<(), CryptoError> {
+        let missing = || CryptoError::HandshakeOutOfOrder;
+        let local_e = self.local_ephemeral.as_ref().map(|(secret, _)| secret);
+        let local_s = &self.local_static.secret;
+        let remote_e = self.remote_ephemeral.as_ref();
+        let remote_s = self.remote_static.as_ref();
+
+        // `es` is always initiator-ephemeral with responder-static, and `se`
+        // the reverse, so each side picks its own half accordingly.
+        let shared = match (token, self.initiator) {
+            (Token::EE, _) => local_e
+                .ok_or_else(missing)?
+                .diffie_hellman(remote_e.ok_or_else(missing)?),
+            (Token::ES, true) | (Token::SE, false) => local_e
+                .ok_or_else(missing)?
+                .diffie_hellman(remote_s.ok_or_else(missing)?),
+            (Token::ES, false) | (Token::SE, true) => {
+                local_s.diffie_hellman(remote_e.ok_or_else(missing)?)
+            }
+            (Token::SS, _) => local_s.diffie_hellman(remote_s.ok_or_else(missing)?),
+            (Token::E | Token::S, _) => unreachable!("not a DH token"),
+        };
+        let shared = check_contributory(shared)?;
+        self.symmetric.mix_key(shared.as_bytes());
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Handshake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Handshake")
+            .field("pattern", &self.pattern)
+            .field("initiator", &self.initiator)
+            .field("message", &self.message)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs a handshake to completion, checking each side's payloads.
+    fn run(initiator: &mut Handshake, responder: &mut Handshake) {
+        let sides = [initiator, responder];
+        let mut turn = 0;
+        while !sides[0].is_finished() {
+            let payload = format!("message {}", turn).into_bytes();
+            let message = sides[turn % 2].write_message(&payload).unwrap();
+            assert_eq!(
+                sides[(turn + 1) % 2].read_message(&message).unwrap(),
+                payload
+            );
+            turn += 1;
+        }
+        assert!(sides[1].is_finished());
+    }
+
+    fn assert_connected(a: &TransportKeys, b: &TransportKeys) {
+        assert_eq!(a.handshake_hash, b.handshake_hash);
+        assert_eq!(a.rekey_secret.as_bytes(), b.rekey_secret.as_bytes());
+        let record = a.send.encrypt_bytes(b"a to b", b"").unwrap();
+        assert_eq!(b.receive.decrypt_bytes(&record, b"").unwrap(), b"a to b");
+        let record = b.send.encrypt_bytes(b"b to a", b"").unwrap();
+        assert_eq!(a.receive.decrypt_bytes(&record, b"").unwrap(), b"b to a");
+
+        // Each direction has its own key.
+        assert_eq!(
+            a.send.decrypt_bytes(&record, b""),
+            Err(CryptoError::AuthenticationFailed)
+        );
+    }
+
+    #[test]
+    fn xx_handshake_establishes_engines() {
+        let alice = StaticKeypair::generate();
+        let bob = StaticKeypair::generate();
+        let mut initiator = Handshake::xx_initiator(&alice);
+        let mut responder = Handshake::xx_responder(&bob);
+        run(&mut initiator, &mut responder);
+
+        let a = initiator.finish().unwrap();
+        let b = responder.finish().unwrap();
+        assert_eq!(a.remote_static, bob.public_key());
+        assert_eq!(b.remote_static, alice.public_key());
+        assert_connected(&a, &b);
+    }
+
+    #[test]
+    fn ik_handshake_establishes_engines() {
+        let alice = StaticKeypair::generate();
+        let bob = StaticKeypair::generate();
+        let mut initiator = Handshake::ik_initiator(&alice, &bob.public_key());
+        let mut responder = Handshake::ik_responder(&bob);
+        run(&mut initiator, &mut responder);
+
+        let a = initiator.finish().unwrap();
+        let b = responder.finish().unwrap();
+        assert_eq!(b.remote_static, alice.public_key());
+        assert_connected(&a, &b);
+    }
+
+    #[test]
+    fn ik_rejects_wrong_responder_key() {
+        let alice = StaticKeypair::generate();
+        let bob = StaticKeypair::generate();
+        let mallory = StaticKeypair::generate();
+        let mut initiator = Handshake::ik_initiator(&alice, &mallory.public_key());
+        let mut responder = Handshake::ik_responder(&bob);
+
+        let message = initiator.write_message(b"").unwrap();
+        assert_eq!(
+            responder.read_message(&message),
+            Err(CryptoError::AuthenticationFailed)
+        );
+    }
+
+    #[test]
+    fn sessions_use_fresh_keys() {
+        let alice = StaticKeypair::generate();
+        let bob = StaticKeypair::generate();
+        let mut hashes = Vec::new();
+        for _ in 0..2 {
+            let mut initiator = Handshake::ik_initiator(&alice, &bob.public_key());
+            let mut responder = Handshake::ik_responder(&bob);
+            run(&mut initiator, &mut responder);
+            let a = initiator.finish().unwrap();
+            hashes.push(a.handshake_hash);
+        }
+        assert_ne!(hashes[0], hashes[1]);
+    }
+
+    #[test]
+    fn tampered_message_fails() {
+        let mut initiator = Handshake::xx_initiator(&StaticKeypair::generate());
+        let mut responder = Handshake::xx_responder(&StaticKeypair::generate());
+        let m1 = initiator.write_message(b"").unwrap();
+        responder.read_message(&m1).unwrap();
+        let mut m2 = responder.write_message(b"hello").unwrap();
+        m2[PUBLIC_KEY_LEN + 3] ^= 1;
+        assert_eq!(
+            initiator.read_message(&m2),
+            Err(CryptoError::AuthenticationFailed)
+        );
+        assert_eq!(
+            initiator.read_message(&m2[..40]),
+            Err(CryptoError::TruncatedInput { len: 40 })
+        );
+    }
+
+    #[test]
+    fn messages_must_alternate() {
+        let mut initiator = Handshake::xx_initiator(&StaticKeypair::generate());
+        let mut responder = Handshake::xx_responder(&StaticKeypair::generate());
+        assert!(initiator.is_my_turn());
+        assert!(!responder.is_my_turn());
+        assert_eq!(
+            responder.write_message(b""),
+            Err(CryptoError::HandshakeOutOfOrder)
+        );
+        assert_eq!(
+            initiator.read_message(&[0u8; 32]),
+            Err(CryptoError::HandshakeOutOfOrder)
+        );
+        initiator.write_message(b"").unwrap();
+        assert_eq!(
+            initiator.finish().err(),
+            Some(CryptoError::HandshakeOutOfOrder)
+        );
+    }
+
+    #[test]
+    fn low_order_ephemeral_is_rejected() {
+        let bob = StaticKeypair::generate();
+        let mut responder = Handshake::ik_responder(&bob);
+        // The all-zero point has small order, so `es` would be all zeros.
+        let message = [0u8; PUBLIC_KEY_LEN + PUBLIC_KEY_LEN + 2 * TAG_LEN];
+        assert_eq!(
+            responder.read_message(&message),
+            Err(CryptoError::InvalidPublicKey)
+        );
+    }
+
+    #[test]
+    fn keypair_round_trips_through_secret_key() {
+        let keypair = StaticKeypair::generate();
+        let restored = StaticKeypair::from_secret(&keypair.secret_key());
+        assert_eq!(restored.public_key(), keypair.public_key());
+        assert!(!format!("{:?}", keypair).contains("secret"));
+    }
+
+    /// Interoperates with the `snow` reference implementation, including the
+    /// transport keys from `split`.
+    fn interop_with_snow(pattern: Pattern, we_initiate: bool) {
+        let ours = StaticKeypair::generate();
+        let name = std::str::from_utf8(pattern.protocol_name()).unwrap();
+        let builder = snow::Builder::new(name.parse().unwrap());
+        let theirs = builder.generate_keypair().unwrap();
+        let their_public: [u8; 32] = theirs.public.as_slice().try_into().unwrap();
+        let builder = snow::Builder::new(name.parse().unwrap()).local_private_key(&theirs.private);
+
+        let (mut handshake, mut snow) = match (pattern, we_initiate) {
+            (Pattern::XX, true) => (
+                Handshake::xx_initiator(&ours),
+                builder.build_responder().unwrap(),
+            ),
+            (Pattern::XX, false) => (
+                Handshake::xx_responder(&ours),
+                builder.build_initiator().unwrap(),
+            ),
+            (Pattern::IK, true) => (
+                Handshake::ik_initiator(&ours, &their_public),
+                builder.build_responder().unwrap(),
+            ),
+            (Pattern::IK, false) => {
+                let public = ours.public_key();
+                let builder = snow::Builder::new(name.parse().unwrap())
+                    .local_private_key(&theirs.private)
+                    .remote_public_key(&public);
+                (
+                    Handshake::ik_responder(&ours),
+                    builder.build_initiator().unwrap(),
+                )
+            }
+        };
+
+        let mut buf = [0u8; 1024];
+        while !handshake.is_finished() {
+            if handshake.is_my_turn() {
+                let message = handshake.write_message(b"ours").unwrap();
+                let n = snow.read_message(&message, &mut buf).unwrap();
+                assert_eq!(&buf[..n], b"ours");
+            } else {
+                let n = snow.write_message(b"theirs", &mut buf).unwrap();
+                assert_eq!(handshake.read_message(&buf[..n]).unwrap(), b"theirs");
+            }
+        }
+        assert!(snow.is_handshake_finished());
+        assert_eq!(handshake.remote_static(), Some(their_public));
+        assert_eq!(snow.get_remote_static(), Some(&ours.public_key()[..]));
+        assert_eq!(&handshake.symmetric.hash[..], snow.get_handshake_hash());
+
+        // snow's first transport message is sealed with its send key and
+        // nonce 0, which must be our receive key.
+        let (initiator_key, responder_key) = handshake.symmetric.split();
+        let receive_key = if we_initiate {
+            responder_key
+        } else {
+            initiator_key
+        };
+        let mut transport = snow.into_transport_mode().unwrap();
+        let n = transport.write_message(b"transport", &mut buf).unwrap();
+        let cipher = CipherSuite::ChaCha20Poly1305.cipher(&SecretKey::from(receive_key));
+        let opened = cipher.open(&noise_nonce(&mut 0).unwrap(), &buf[..n], b"");
+        assert_eq!(opened.unwrap(), b"transport");
+    }
+
+    #[test]
+    fn xx_interoperates_with_reference() {
+        interop_with_snow(Pattern::XX, true);
+        interop_with_snow(Pattern::XX, false);
+    }
+
+    #[test]
+    fn ik_interoperates_with_reference() {
+        interop_with_snow(Pattern::IK, true);
+        interop_with_snow(Pattern::IK, false);
+    }
+}
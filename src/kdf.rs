@@ -0,0 +1,165 @@
+//! Key derivation for [`CryptoEngine`](crate::CryptoEngine).
+//!
+//! Two sources are supported:
+//!
+//! * passphrases, stretched with Argon2id and a per-deployment salt, and
+//! * existing high-entropy keying material (for example a shared secret from
+//!   a key exchange), expanded with HKDF-SHA256 under a context label.
+//!
+//! Derived keys are returned as [`SecretKey`]s so they are wiped as soon as
+//! the caller drops them. Intermediate HMAC state inside the `hkdf` crate is
+//! not wiped; see [`derive_from_secret`].
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use zeroize::Zeroize;
+
+use crate::engine::KEY_LEN;
+use crate::error::CryptoError;
+use crate::key::SecretKey;
+
+/// Minimum salt length accepted for passphrase derivation.
+pub const MIN_SALT_LEN: usize = 8;
+
+/// Cost parameters for Argon2id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Argon2Params {
+    /// Memory cost in KiB.
+    pub memory_kib: u32,
+    /// Number of passes over memory.
+    pub iterations: u32,
+    /// Degree of parallelism (lanes).
+    pub parallelism: u32,
+}
+
+impl Default for Argon2Params {
+    /// OWASP's baseline recommendation: 19 MiB, two passes, one lane.
+    fn default() -> Self {
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+/// Derives a 32-byte key from a passphrase and salt with Argon2id.
+pub fn derive_from_passphrase(
+    passphrase: &[u8],
+    salt: &[u8],
+    params: &Argon2Params,
+) -> Result<SecretKey, CryptoError> {
+    if salt.len() < MIN_SALT_LEN {
+        return Err(CryptoError::KeyDerivationFailed);
+    }
+    let params = Params::new(
+        params.memory_kib,
+        params.iterations,
+        params.parallelism,
+        Some(KEY_LEN),
+    )
+    .map_err(|_| CryptoError::KeyDerivationFailed)?;
+
+    let mut key = SecretKey::from([0u8; KEY_LEN]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase, salt, key.as_bytes_mut())
+        .map_err(|_| CryptoError::KeyDerivationFailed)?;
+    Ok(key)
+}
+
+/// Derives a 32-byte key from existing keying material with HKDF-SHA256.
+///
+/// `context` is the HKDF info label; distinct labels yield independent keys
+/// from the same secret. The extracted PRK is wiped, but the HMAC state
+/// inside `hkdf` is not (it does not support zeroization).
+pub fn derive_from_secret(
+    secret: &[u8],
+    salt: Option<&[u8]>,
+    context: &[u8],
+) -> Result<SecretKey, CryptoError> {
+    let mut key = SecretKey::from([0u8; KEY_LEN]);
+    hkdf_sha256(salt, secret, context, key.as_bytes_mut())?;
+    Ok(key)
+}
+
+/// HKDF-SHA256 extract-then-expand of `ikm` into `okm`.
+///
+/// The extracted PRK is wiped before returning. `hkdf` and `hmac` do not
+/// zeroize, though, so the HMAC state keyed with the PRK can remain in
+/// freed stack memory; derivation is not guaranteed to leave no secret
+/// material behind.
+pub(crate) fn hkdf_sha256(
+    salt: Option<&[u8]>,
+    ikm: &[u8],
+    info: &[u8],
+    okm: &mut [u8],
+) -> Result<(), CryptoError> {
+    let (mut prk, hkdf) = Hkdf::<Sha256>::extract(salt, ikm);
+    prk.as_mut_slice().zeroize();
+    hkdf.expand(info, okm)
+        .map_err(|_| CryptoError::KeyDerivationFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cheap() -> Argon2Params {
+        Argon2Params {
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+        }
+    }
+
+    #[test]
+    fn passphrase_derivation_is_deterministic() {
+        let a = derive_from_passphrase(b"hunter2", b"salt-1234", &cheap()).unwrap();
+        let b = derive_from_passphrase(b"hunter2", b"salt-1234", &cheap()).unwrap();
+        let c = derive_from_passphrase(b"hunter2", b"salt-5678", &cheap()).unwrap();
+        assert_eq!(a.as_bytes(), b.as_bytes());
+        assert_ne!(a.as_bytes(), c.as_bytes());
+    }
+
+    #[test]
+    fn passphrase_derivation_rejects_short_salt() {
+        assert_eq!(
+            derive_from_passphrase(b"hunter2", b"salt", &cheap()).map(|_| ()),
+            Err(CryptoError::KeyDerivationFailed)
+        );
+    }
+
+    #[test]
+    fn passphrase_derivation_rejects_invalid_params() {
+        let params = Argon2Params {
+            memory_kib: 1,
+            ..cheap()
+        };
+        assert!(derive_from_passphrase(b"hunter2", b"salt-1234", &params).is_err());
+    }
+
+    #[test]
+    fn secret_derivation_separates_contexts() {
+        let a = derive_from_secret(b"shared secret", None, b"vpn data").unwrap();
+        let b = derive_from_secret(b"shared secret", None, b"vpn control").unwrap();
+        assert_ne!(a.as_bytes(), b.as_bytes());
+    }
+
+    /// RFC 5869, Appendix A.1, truncated to 32 bytes of output.
+    #[test]
+    fn secret_derivation_known_answer() {
+        let ikm = [0x0bu8; 22];
+        let salt: Vec<u8> = (0x00..=0x0c).collect();
+        let info: Vec<u8> = (0xf0..=0xf9).collect();
+        let key = derive_from_secret(&ikm, Some(&salt), &info).unwrap();
+        assert_eq!(
+            key.as_bytes(),
+            &[
+                0x3c, 0xb2, 0x5f, 0x25, 0xfa, 0xac, 0xd5, 0x7a, 0x90, 0x43, 0x4f, 0x64, 0xd0, 0x36,
+                0x2f, 0x2a, 0x2d, 0x2d, 0x0a, 0x90, 0xcf, 0x1a, 0x5a, 0x4c, 0x5d, 0xb0, 0x2d, 0x56,
+                0xec, 0xc4, 0xc5, 0xbf
+            ]
+        );
+    }
+}
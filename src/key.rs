@@ -0,0 +1,75 @@
+//! Secret key material that is wiped from memory when dropped.
+
+use std::fmt;
+
+use rand::{RngCore, rngs::OsRng};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::engine::KEY_LEN;
+
+/// A 32-byte symmetric key.
+///
+/// The bytes are zeroized on drop and never printed by `Debug`. Construct
+/// one from raw bytes with `SecretKey::from`, from a key derivation function
+/// in [`kdf`](crate::kdf), or at random with [`SecretKey::generate`].
+pub struct SecretKey([u8; KEY_LEN]);
+
+impl SecretKey {
+    /// Generates a fresh random key from the operating system RNG.
+    pub fn generate() -> Self {
+        let mut key = Self([0u8; KEY_LEN]);
+        OsRng.fill_bytes(&mut key.0);
+        key
+    }
+
+    /// Returns the raw key bytes.
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+
+    pub(crate) fn as_bytes_mut(&mut self) -> &mut [u8; KEY_LEN] {
+        &mut self.0
+    }
+}
+
+impl From<[u8; KEY_LEN]> for SecretKey {
+    /// Takes ownership of `bytes`. The caller's copy, if any, is not wiped.
+    fn from(bytes: [u8; KEY_LEN]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for SecretKey {}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey([REDACTED])")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_is_redacted() {
+        let key = SecretKey::from([0x41u8; KEY_LEN]);
+        let printed = format!("{:?}", key);
+        assert_eq!(printed, "SecretKey([REDACTED])");
+        assert!(!printed.contains("65"));
+    }
+
+    #[test]
+    fn generated_keys_differ() {
+        assert_ne!(
+            SecretKey::generate().as_bytes(),
+            SecretKey::generate().as_bytes()
+        );
+    }
+}
@@ -0,0 +1,585 @@
+//! Key files: keys saved to disk, optionally encrypted under a passphrase.
+//!
+//! A key file starts with a fixed 20-byte header:
+//!
+//! ```text
+//! offset  size  field
+//!      0     4  magic "VPNK"
+//!      4     1  format version
+//!      5     1  cipher suite id (see CipherSuite)
+//!      6     1  intended usage (see KeyUsage)
+//!      7     1  protection: 0 = plain, 1 = passphrase
+//!      8     4  key id (big endian)
+//!     12     8  creation time, seconds since the Unix epoch (big endian)
+//! ```
+//!
+//! A plain file follows it with the 32 key bytes. A passphrase-protected
+//! file follows it with the Argon2id parameters (memory in KiB, iterations
+//! and parallelism, each a big-endian `u32`), a 16-byte salt, and the key
+//! sealed as an XChaCha20-Poly1305 record under the passphrase-derived key,
+//! with everything before the record as AAD. A wrong passphrase and a
+//! tampered file both fail with [`CryptoError::AuthenticationFailed`].
+//!
+//! [`KeyFile::save`] creates files readable only by their owner, and on
+//! Unix [`KeyFile::load`] refuses files that other users can access.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::{RngCore, rngs::OsRng};
+use zeroize::Zeroize;
+
+use crate::engine::{CryptoEngine, KEY_LEN};
+use crate::error::CryptoError;
+use crate::format::CipherSuite;
+use crate::kdf::Argon2Params;
+use crate::key::SecretKey;
+
+/// Magic bytes identifying a key file.
+pub const MAGIC: [u8; 4] = *b"VPNK";
+
+/// Current key file format version.
+pub const VERSION: u8 = 1;
+
+/// Size of the key file header in bytes.
+pub const HEADER_LEN: usize = 20;
+
+/// Length of the salt in passphrase-protected key files.
+pub const SALT_LEN: usize = 16;
+
+/// Largest Argon2id memory cost accepted when loading, in KiB (1 GiB), so a
+/// crafted file cannot make loading exhaust memory.
+pub const MAX_MEMORY_KIB: u32 = 1024 * 1024;
+
+/// Largest Argon2id iteration count accepted when loading, so a crafted file
+/// cannot make loading run indefinitely.
+pub const MAX_ITERATIONS: u32 = 64;
+
+/// Largest Argon2id parallelism accepted when loading.
+pub const MAX_PARALLELISM: u32 = 64;
+
+const PLAIN: u8 = 0;
+const PASSPHRASE: u8 = 1;
+const PARAMS_LEN: usize = 12;
+
+/// What a key is meant to be used for. Recorded in the file for the
+/// operator's benefit; it is not enforced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum KeyUsage {
+    /// No particular use.
+    Any = 0,
+    /// Sealing individual records with a [`CryptoEngine`].
+    Records = 1,
+    /// Encrypting [`stream`](crate::stream)s.
+    Streams = 2,
+    /// Shared secret for [`Session::from_secret`](crate::session::Session::from_secret).
+    Sessions = 3,
+}
+
+impl KeyUsage {
+    /// Returns the usage for a file id.
+    pub fn from_id(id: u8) -> Result<Self, CryptoError> {
+        match id {
+            0 => Ok(KeyUsage::Any),
+            1 => Ok(KeyUsage::Records),
+            2 => Ok(KeyUsage::Streams),
+            3 => Ok(KeyUsage::Sessions),
+            _ => Err(CryptoError::InvalidKeyFile),
+        }
+    }
+
+    /// Returns the file id of the usage.
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+}
+
+/// A key with the metadata stored alongside it in a key file.
+#[derive(Debug)]
+pub struct KeyFile {
+    key: SecretKey,
+    suite: CipherSuite,
+    usage: KeyUsage,
+    key_id: u32,
+    created: SystemTime,
+}
+
+impl KeyFile {
+    /// Wraps `key` for XChaCha20-Poly1305, any usage and key id `0`,
+    /// created now.
+    pub fn new(key: SecretKey) -> Self {
+        Self {
+            key,
+            suite: CipherSuite::XChaCha20Poly1305,
+            usage: KeyUsage::Any,
+            key_id: 0,
+            created: SystemTime::now(),
+        }
+    }
+
+    /// Creates a key file for a fresh random key.
+    pub fn generate() -> Self {
+        Self::new(SecretKey::generate())
+    }
+
+    /// Sets the cipher suite the key is for.
+    pub fn with_suite(mut self, suite: CipherSuite) -> Self {
+        self.suite = suite;
+        self
+    }
+
+    /// Sets the intended usage.
+    pub fn with_usage(mut self, usage: KeyUsage) -> Self {
+        self.usage = usage;
+        self
+    }
+
+    /// Sets the key id.
+    pub fn with_key_id(mut self, key_id: u32) -> Self {
+        self.key_id = key_id;
+        self
+    }
+
+    /// Returns the key.
+    pub fn key(&self) -> &SecretKey {
+        &self.key
+    }
+
+    /// Returns the cipher suite the key is for.
+    pub fn suite(&self) -> CipherSuite {
+        self.suite
+    }
+
+    /// Returns the intended usage.
+    pub fn usage(&self) -> KeyUsage {
+        self.usage
+    }
+
+    /// Returns the key id.
+    pub fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    /// Returns when the key was created, to the second.
+    pub fn created(&self) -> SystemTime {
+        self.created
+    }
+
+    /// Builds an engine for the key's suite and key id.
+    pub fn engine(&self) -> CryptoEngine {
+        CryptoEngine::with_suite(&self.key, self.suite).with_key_id(self.key_id)
+    }
+
+    /// Encodes the key file with the key in the clear.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = self.header(PLAIN).to_vec();
+        out.extend_from_slice(self.key.as_bytes());
+        out
+    }
+
+    /// Encodes the key file with the key sealed under a key derived from
+    /// `passphrase` with Argon2id.
+    pub fn encode_with_passphrase(
+        &self,
+        passphrase: &[u8],
+        params: &Argon2Params,
+    ) -> Result<Vec<u8>, CryptoError> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let mut out = self.header(PASSPHRASE).to_vec();
+        for value in [params.memory_kib, params.iterations, params.parallelism] {
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+        out.extend_from_slice(&salt);
+
+        let wrapping = CryptoEngine::from_passphrase(passphrase, &salt, params)?;
+        let sealed = wrapping.encrypt_bytes(self.key.as_bytes(), &out)?;
+        out.extend_from_slice(&sealed);
+        Ok(out)
+    }
+
+    /// Decodes a plain key file. Passphrase-protected files fail with
+    /// [`CryptoError::PassphraseRequired`].
+    pub fn decode(data: &[u8]) -> Result<Self, CryptoError> {
+        let (mut file, protection) = Self::decode_header(data)?;
+        if protection == PASSPHRASE {
+            return Err(CryptoError::PassphraseRequired);
+        }
+        let key: [u8; KEY_LEN] = data[HEADER_LEN..]
+            .try_into()
+            .map_err(|_| CryptoError::InvalidKeyFile)?;
+        file.key = SecretKey::from(key);
+        Ok(file)
+    }
+
+    /// Decodes a key file, unsealing the key with `passphrase` if the file
+    /// is protected. Plain files are accepted as they are.
+    pub fn decode_with_passphrase(data: &[u8], passphrase: &[u8]) -> Result<Self, CryptoError> {
+        let (mut file, protection) = Self::decode_header(data)?;
+        if protection == PLAIN {
+            return Self::decode(data);
+        }
+
+        let sealed_at = HEADER_LEN + PARAMS_LEN + SALT_LEN;
+        if data.len() < sealed_at {
+            return Err(CryptoError::InvalidKeyFile);
+        }
+        let param = |i: usize| {
+            let at = HEADER_LEN + 4 * i;
+            u32::from_be_bytes(data[at..at + 4].try_into().expect("4 bytes"))
+        };
+        let params = Argon2Params {
+            memory_kib: param(0),
+            iterations: param(1),
+            parallelism: param(2),
+        };
+        if params.memory_kib > MAX_MEMORY_KIB
+            || params.iterations > MAX_ITERATIONS
+            || params.parallelism > MAX_PARALLELISM
+        {
+            return Err(CryptoError::InvalidKeyFile);
+        }
+        let salt = &data[HEADER_LEN + PARAMS_LEN..sealed_at];
+
+        let wrapping = CryptoEngine::from_passphrase(passphrase, salt, &params)?;
+        let mut key = wrapping.decrypt_bytes(&data[sealed_at..], &data[..sealed_at])?;
+        let bytes: Result<[u8; KEY_LEN], _> = key.as_slice().try_into();
+        key.zeroize();
+        file.key = SecretKey::from(bytes.map_err(|_| CryptoError::InvalidKeyFile)?);
+        Ok(file)
+    }
+
+    /// Writes a plain key file to `path`, readable and writable only by its
+    /// owner. An existing file is replaced.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut data = self.encode();
+        let result = write_private(path.as_ref(), &data);
+        data.zeroize();
+        result
+    }
+
+    /// Writes a passphrase-protected key file to `path`, readable and
+    /// writable only by its owner. An existing file is replaced.
+    pub fn save_with_passphrase(
+        &self,
+        path: impl AsRef<Path>,
+        passphrase: &[u8],
+        params: &Argon2Params,
+    ) -> io::Result<()> {
+        let data = self
+            .encode_with_passphrase(passphrase, params)
+            .map_err(invalid_data)?;
+        write_private(path.as_ref(), &data)
+    }
+
+    /// Reads a plain key file.
+    ///
+    /// Fails with [`io::ErrorKind::PermissionDenied`] if other users can
+    /// access the file (Unix only), and with [`io::ErrorKind::InvalidData`]
+    /// wrapping a [`CryptoError`] if its contents are not a plain key file.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut data = read_private(path.as_ref())?;
+        let result = Self::decode(&data).map_err(invalid_data);
+        data.zeroize();
+        result
+    }
+
+    /// Reads a key file, unsealing it with `passphrase` if it is protected.
+    /// Fails like [`load`](Self::load).
+    pub fn load_with_passphrase(path: impl AsRef<Path>, passphrase: &[u8]) -> io::Result<Self> {
+        let mut data = read_private(path.as_ref())?;
+        let result = Self::decode_with_passphrase(&data, passphrase).map_err(invalid_data);
+        data.zeroize();
+        result
+    }
+
+    fn header(&self, protection: u8) -> [u8; HEADER_LEN] {
+        let created = self
+            .created
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut out = [0u8; HEADER_LEN];
+        out[..4].copy_from_slice(&MAGIC);
+        out[4] = VERSION;
+        out[5] = self.suite.id();
+        out[6] = self.usage.id();
+        out[7] = protection;
+        out[8..12].copy_from_slice(&self.key_id.to_be_bytes());
+        out[12..20].copy_from_slice(&created.to_be_bytes());
+        out
+    }
+
+    /// Parses the header, returning the metadata (with a placeholder key)
+    /// and the protection byte.
+    fn decode_header(data: &[u8]) -> Result<(Self, u8), CryptoError> {
+        if data.len() < HEADER_LEN || data[..4] != MAGIC {
+            return Err(CryptoError::InvalidKeyFile);
+        }
+        let version = data[4];
+        if version != VERSION {
+            return Err(CryptoError::UnsupportedVersion { version });
+        }
+        let protection = data[7];
+        if protection != PLAIN && protection != PASSPHRASE {
+            return Err(CryptoError::InvalidKeyFile);
+        }
+        let created = u64::from_be_bytes(data[12..20].try_into().expect("8 bytes"));
+        let file = Self {
+            key: SecretKey::from([0u8; KEY_LEN]),
+            suite: CipherSuite::from_id(data[5])?,
+            usage: KeyUsage::from_id(data[6])?,
+            key_id: u32::from_be_bytes(data[8..12].try_into().expect("4 bytes")),
+            created: UNIX_EPOCH
+                .checked_add(Duration::from_secs(created))
+                .ok_or(CryptoError::InvalidKeyFile)?,
+        };
+        Ok((file, protection))
+    }
+}
+
+fn invalid_data(e: CryptoError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        let file = options.open(path)?;
+        // `mode` only applies to new files; tighten an existing one too.
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        write_all(file, data)
+    }
+    #[cfg(not(unix))]
+    write_all(options.open(path)?, data)
+}
+
+fn write_all(mut file: File, data: &[u8]) -> io::Result<()> {
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+fn read_private(path: &Path) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // Checked on the open handle, so the file cannot be swapped in between.
+        let mode = file.metadata()?.permissions().mode();
+        // Group access counts too, as it does for ssh keys.
+        if mode & 0o077 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "key file {} is accessible by other users (mode {:03o}); restrict it to its owner",
+                    path.display(),
+                    mode & 0o777
+                ),
+            ));
+        }
+    }
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cheap() -> Argon2Params {
+        Argon2Params {
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+        }
+    }
+
+    fn sample() -> KeyFile {
+        KeyFile::new(SecretKey::from([7u8; KEY_LEN]))
+            .with_suite(CipherSuite::Aes256Gcm)
+            .with_usage(KeyUsage::Records)
+            .with_key_id(9)
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "vpn-encrypt-keyfile-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn assert_same(a: &KeyFile, b: &KeyFile) {
+        assert_eq!(a.key().as_bytes(), b.key().as_bytes());
+        assert_eq!(a.suite(), b.suite());
+        assert_eq!(a.usage(), b.usage());
+        assert_eq!(a.key_id(), b.key_id());
+        assert_eq!(
+            a.created().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            b.created().duration_since(UNIX_EPOCH).unwrap().as_secs()
+        );
+    }
+
+    #[test]
+    fn plain_round_trip() {
+        let file = sample();
+        let data = file.encode();
+        assert_eq!(data.len(), HEADER_LEN + KEY_LEN);
+        assert_eq!(&data[..4], b"VPNK");
+        assert_same(&KeyFile::decode(&data).unwrap(), &file);
+    }
+
+    #[test]
+    fn passphrase_round_trip() {
+        let file = sample();
+        let data = file.encode_with_passphrase(b"hunter2", &cheap()).unwrap();
+        assert!(!data.windows(KEY_LEN).any(|w| w == file.key().as_bytes()));
+        assert_eq!(
+            KeyFile::decode(&data).unwrap_err(),
+            CryptoError::PassphraseRequired
+        );
+        assert_same(
+            &KeyFile::decode_with_passphrase(&data, b"hunter2").unwrap(),
+            &file,
+        );
+        assert_eq!(
+            KeyFile::decode_with_passphrase(&data, b"hunter3").unwrap_err(),
+            CryptoError::AuthenticationFailed
+        );
+    }
+
+    #[test]
+    fn protected_metadata_is_authenticated() {
+        let mut data = sample().encode_with_passphrase(b"pw", &cheap()).unwrap();
+        data[11] ^= 1; // key id
+        assert_eq!(
+            KeyFile::decode_with_passphrase(&data, b"pw").unwrap_err(),
+            CryptoError::AuthenticationFailed
+        );
+    }
+
+    #[test]
+    fn decode_rejects_malformed_files() {
+        let data = sample().encode();
+        assert_eq!(
+            KeyFile::decode(&data[..30]).unwrap_err(),
+            CryptoError::InvalidKeyFile
+        );
+        assert_eq!(
+            KeyFile::decode(b"not a key file").unwrap_err(),
+            CryptoError::InvalidKeyFile
+        );
+        let mut bad = data.clone();
+        bad[4] = 2;
+        assert_eq!(
+            KeyFile::decode(&bad).unwrap_err(),
+            CryptoError::UnsupportedVersion { version: 2 }
+        );
+        let mut bad = data.clone();
+        bad[6] = 0xee;
+        assert_eq!(
+            KeyFile::decode(&bad).unwrap_err(),
+            CryptoError::InvalidKeyFile
+        );
+    }
+
+    #[test]
+    fn decode_rejects_unrepresentable_creation_time() {
+        let mut plain = sample().encode();
+        plain[12..20].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert_eq!(
+            KeyFile::decode(&plain).unwrap_err(),
+            CryptoError::InvalidKeyFile
+        );
+
+        let mut protected = sample().encode_with_passphrase(b"pw", &cheap()).unwrap();
+        protected[12..20].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert_eq!(
+            KeyFile::decode_with_passphrase(&protected, b"pw").unwrap_err(),
+            CryptoError::InvalidKeyFile
+        );
+    }
+
+    #[test]
+    fn decode_rejects_excessive_argon2_memory() {
+        let mut data = sample().encode_with_passphrase(b"pw", &cheap()).unwrap();
+        data[HEADER_LEN..HEADER_LEN + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(
+            KeyFile::decode_with_passphrase(&data, b"pw").unwrap_err(),
+            CryptoError::InvalidKeyFile
+        );
+    }
+
+    #[test]
+    fn decode_rejects_excessive_argon2_iterations() {
+        let mut data = sample().encode_with_passphrase(b"pw", &cheap()).unwrap();
+        data[HEADER_LEN + 4..HEADER_LEN + 8].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(
+            KeyFile::decode_with_passphrase(&data, b"pw").unwrap_err(),
+            CryptoError::InvalidKeyFile
+        );
+    }
+
+    #[test]
+    fn decode_rejects_excessive_argon2_parallelism() {
+        let mut data = sample().encode_with_passphrase(b"pw", &cheap()).unwrap();
+        data[HEADER_LEN + 8..HEADER_LEN + 12].copy_from_slice(&(MAX_PARALLELISM + 1).to_be_bytes());
+        assert_eq!(
+            KeyFile::decode_with_passphrase(&data, b"pw").unwrap_err(),
+            CryptoError::InvalidKeyFile
+        );
+    }
+
+    #[test]
+    fn engine_uses_suite_and_key_id() {
+        let engine = sample().engine();
+        assert_eq!(engine.suite(), CipherSuite::Aes256Gcm);
+        assert_eq!(engine.key_id(), 9);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = temp_path("save");
+        let file = sample();
+        file.save(&path).unwrap();
+        assert_same(&KeyFile::load(&path).unwrap(), &file);
+
+        file.save_with_passphrase(&path, b"pw", &cheap()).unwrap();
+        let err = KeyFile::load(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_same(&KeyFile::load_with_passphrase(&path, b"pw").unwrap(), &file);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn load_refuses_files_other_users_can_read() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_path("perms");
+        sample().save(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        for mode in [0o644, 0o640, 0o604] {
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+            let err = KeyFile::load(&path).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied, "{mode:o}");
+        }
+
+        // Saving over it restores owner-only permissions.
+        sample().save(&path).unwrap();
+        assert!(KeyFile::load(&path).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+//! Multiple keys tagged with ids, for decrypting during key rotation.
+//!
+//! A [`Keyring`] seals new records under its active key and opens records
+//! under whichever key the record header names. Rotating is a matter of
+//! inserting the new key, making it active and retiring the old one with a
+//! grace period so in-flight packets still decrypt.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::engine::CryptoEngine;
+use crate::error::CryptoError;
+use crate::format::{self, CipherSuite, Header};
+use crate::key::SecretKey;
+
+struct Entry {
+    engine: CryptoEngine,
+    retire_at: Option<Instant>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.retire_at.is_some_and(|deadline| now >= deadline)
+    }
+}
+
+/// A set of keys indexed by key id, with one active key for encryption.
+#[derive(Default)]
+pub struct Keyring {
+    entries: BTreeMap<u32, Entry>,
+    active: Option<u32>,
+}
+
+impl Keyring {
+    /// Creates an empty keyring.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `key` under `key_id`, replacing any key already stored there.
+    ///
+    /// The first key inserted becomes the active key.
+    pub fn insert(&mut self, key_id: u32, key: &SecretKey) {
+        self.insert_with_suite(key_id, key, CipherSuite::XChaCha20Poly1305);
+    }
+
+    /// Adds `key` under `key_id` for an explicit cipher suite, replacing any
+    /// key already stored there.
+    pub fn insert_with_suite(&mut self, key_id: u32, key: &SecretKey, suite: CipherSuite) {
+        let engine = CryptoEngine::with_suite(key, suite).with_key_id(key_id);
+        self.entries.insert(
+            key_id,
+            Entry {
+                engine,
+                retire_at: None,
+            },
+        );
+        self.active.get_or_insert(key_id);
+    }
+
+    /// Makes `key_id` the key used for encryption.
+    pub fn set_active(&mut self, key_id: u32) -> Result<(), CryptoError> {
+        match self.entries.get_mut(&key_id) {
+            Some(entry) => {
+                entry.retire_at = None;
+                self.active = Some(key_id);
+                Ok(())
+            }
+            None => Err(CryptoError::UnknownKeyId { key_id }),
+        }
+    }
+
+    /// Returns the id of the key used for encryption, if any.
+    pub fn active_key_id(&self) -> Option<u32> {
+        self.active
+    }
+
+    /// Returns `true` if the keyring holds a key with `key_id`.
+    pub fn contains(&self, key_id: u32) -> bool {
+        self.entries.contains_key(&key_id)
+    }
+
+    /// Returns the ids of all keys currently held, in ascending order.
+    pub fn key_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.entries.keys().copied()
+    }
+
+    /// Schedules `key_id` for removal once `grace` has elapsed.
+    ///
+    /// The key keeps decrypting until then. The active key cannot be retired.
+    pub fn retire(&mut self, key_id: u32, grace: Duration) -> Result<(), CryptoError> {
+        if self.active == Some(key_id) {
+            return Err(CryptoError::ActiveKeyInUse { key_id });
+        }
+        let entry = self
+            .entries
+            .get_mut(&key_id)
+            .ok_or(CryptoError::UnknownKeyId { key_id })?;
+        entry.retire_at = Some(Instant::now() + grace);
+        Ok(())
+    }
+
+    /// Removes `key_id` immediately. The active key cannot be removed.
+    pub fn remove(&mut self, key_id: u32) -> Result<(), CryptoError> {
+        if self.active == Some(key_id) {
+            return Err(CryptoError::ActiveKeyInUse { key_id });
+        }
+        self.entries
+            .remove(&key_id)
+            .map(|_| ())
+            .ok_or(CryptoError::UnknownKeyId { key_id })
+    }
+
+    /// Drops every retired key whose grace period has ended and returns
+    /// their ids.
+    pub fn remove_expired(&mut self) -> Vec<u32> {
+        let now = Instant::now();
+        let expired: Vec<u32> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(&key_id, _)| key_id)
+            .collect();
+        for key_id in &expired {
+            self.entries.remove(key_id);
+        }
+        expired
+    }
+
+    /// Encrypts a UTF-8 message under the active key.
+    pub fn encrypt(&self, message: &str, aad: impl AsRef<[u8]>) -> Result<Vec<u8>, CryptoError> {
+        self.encrypt_bytes(message.as_bytes(), aad.as_ref())
+    }
+
+    /// Decrypts a record under the key named in its header.
+    pub fn decrypt(&self, data: &[u8], aad: impl AsRef<[u8]>) -> Result<String, CryptoError> {
+        let plaintext = self.decrypt_bytes(data, aad.as_ref())?;
+        String::from_utf8(plaintext).map_err(|_| CryptoError::InvalidUtf8)
+    }
+
+    /// Encrypts a binary payload under the active key.
+    pub fn encrypt_bytes(&self, message: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.active_engine()?.encrypt_bytes(message, aad)
+    }
+
+    /// Decrypts a record under the key named in its header.
+    ///
+    /// Legacy headerless records carry no key id and are opened with the
+    /// active key. As with [`CryptoEngine::decrypt_bytes`], a record whose
+    /// header does not parse or names no usable key is retried as legacy
+    /// before the original error is reported. Keys whose grace period has ended are not used even if
+    /// [`remove_expired`](Self::remove_expired) has not run yet.
+    pub fn decrypt_bytes(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if !format::has_header(data) {
+            return self.active_engine()?.decrypt_bytes(data, aad);
+        }
+        let entry = Header::parse(data).and_then(|header| {
+            let key_id = header.key_id;
+            self.entries
+                .get(&key_id)
+                .filter(|entry| !entry.is_expired(Instant::now()))
+                .ok_or(CryptoError::UnknownKeyId { key_id })
+        });
+        match entry {
+            Ok(entry) => entry.engine.decrypt_bytes(data, aad),
+            // A legacy nonce can start with the header magic by chance.
+            Err(e) => self
+                .active_engine()
+                .and_then(|engine| engine.decrypt_bytes(data, aad))
+                .map_err(|_| e),
+        }
+    }
+
+    fn active_engine(&self) -> Result<&CryptoEngine, CryptoError> {
+        self.active
+            .and_then(|key_id| self.entries.get(&key_id))
+            .map(|entry| &entry.engine)
+            .ok_or(CryptoError::NoActiveKey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::KEY_LEN;
+
+    fn key(byte: u8) -> SecretKey {
+        SecretKey::from([byte; KEY_LEN])
+    }
+
+    #[test]
+    fn encrypts_under_active_key() {
+        let mut keyring = Keyring::new();
+        keyring.insert(1, &key(1));
+        keyring.insert(2, &key(2));
+        assert_eq!(keyring.active_key_id(), Some(1));
+
+        keyring.set_active(2).unwrap();
+        let record = keyring.encrypt_bytes(b"payload", b"").unwrap();
+        assert_eq!(Header::parse(&record).unwrap().key_id, 2);
+    }
+
+    #[test]
+    fn decrypts_under_old_and_new_keys() {
+        let mut keyring = Keyring::new();
+        keyring.insert(1, &key(1));
+        let old = keyring.encrypt_bytes(b"old", b"aad").unwrap();
+
+        keyring.insert(2, &key(2));
+        keyring.set_active(2).unwrap();
+        keyring.retire(1, Duration::from_secs(60)).unwrap();
+        let new = keyring.encrypt_bytes(b"new", b"aad").unwrap();
+
+        assert_eq!(keyring.decrypt_bytes(&old, b"aad").unwrap(), b"old");
+        assert_eq!(keyring.decrypt_bytes(&new, b"aad").unwrap(), b"new");
+    }
+
+    #[test]
+    fn expired_keys_stop_decrypting_and_are_removed() {
+        let mut keyring = Keyring::new();
+        keyring.insert(1, &key(1));
+        let old = keyring.encrypt_bytes(b"old", b"").unwrap();
+        keyring.insert(2, &key(2));
+        keyring.set_active(2).unwrap();
+
+        keyring.retire(1, Duration::ZERO).unwrap();
+        assert_eq!(
+            keyring.decrypt_bytes(&old, b""),
+            Err(CryptoError::UnknownKeyId { key_id: 1 })
+        );
+        assert_eq!(keyring.remove_expired(), vec![1]);
+        assert!(!keyring.contains(1));
+    }
+
+    #[test]
+    fn active_key_cannot_be_retired_or_removed() {
+        let mut keyring = Keyring::new();
+        keyring.insert(1, &key(1));
+        assert_eq!(
+            keyring.retire(1, Duration::ZERO),
+            Err(CryptoError::ActiveKeyInUse { key_id: 1 })
+        );
+        assert_eq!(
+            keyring.remove(1),
+            Err(CryptoError::ActiveKeyInUse { key_id: 1 })
+        );
+    }
+
+    #[test]
+    fn keys_can_use_different_suites() {
+        let mut keyring = Keyring::new();
+        keyring.insert(1, &key(1));
+        let old = keyring.encrypt_bytes(b"old", b"").unwrap();
+        keyring.insert_with_suite(2, &key(2), CipherSuite::Aes256Gcm);
+        keyring.set_active(2).unwrap();
+        let new = keyring.encrypt_bytes(b"new", b"").unwrap();
+
+        assert_eq!(Header::parse(&new).unwrap().suite, CipherSuite::Aes256Gcm);
+        assert_eq!(keyring.decrypt_bytes(&old, b"").unwrap(), b"old");
+        assert_eq!(keyring.decrypt_bytes(&new, b"").unwrap(), b"new");
+    }
+
+    #[test]
+    fn empty_keyring_cannot_encrypt() {
+        assert_eq!(
+            Keyring::new().encrypt_bytes(b"payload", b""),
+            Err(CryptoError::NoActiveKey)
+        );
+    }
+
+    #[test]
+    fn unknown_key_id_is_reported() {
+        let record = CryptoEngine::new(&key(9))
+            .with_key_id(9)
+            .encrypt_bytes(b"payload", b"")
+            .unwrap();
+        let mut keyring = Keyring::new();
+        keyring.insert(1, &key(1));
+        assert_eq!(
+            keyring.decrypt_bytes(&record, b""),
+            Err(CryptoError::UnknownKeyId { key_id: 9 })
+        );
+    }
+
+    #[test]
+    fn decrypts_legacy_record_with_magic_nonce() {
+        use chacha20poly1305::aead::{Aead, KeyInit};
+        use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+        let mut nonce = [0u8; 24];
+        nonce[..4].copy_from_slice(&format::MAGIC);
+        let mut legacy = nonce.to_vec();
+        legacy.extend(
+            XChaCha20Poly1305::new(&[1u8; KEY_LEN].into())
+                .encrypt(XNonce::from_slice(&nonce), &b"old data"[..])
+                .unwrap(),
+        );
+        assert!(format::has_header(&legacy));
+
+        let mut keyring = Keyring::new();
+        keyring.insert(1, &key(1));
+        assert_eq!(keyring.decrypt_bytes(&legacy, b"").unwrap(), b"old data");
+    }
+}
@@ -0,0 +1,46 @@
+//! Authenticated encryption for VPN traffic.
+//!
+//! The crate wraps the XChaCha20-Poly1305 AEAD cipher in a small
+//! [`CryptoEngine`] that produces self-contained records of the form
+//! `[header][24-byte nonce][ciphertext + tag]`. The header layout is
+//! described in [`format`].
+//!
+//! ```
+//! use vpn_encrypt::{CryptoEngine, SecretKey};
+//!
+//! let engine = CryptoEngine::new(&SecretKey::generate());
+//! let record = engine.encrypt("Hello, VPN!", "vpn-auth")?;
+//! assert_eq!(engine.decrypt(&record, "vpn-auth")?, "Hello, VPN!");
+//! # Ok::<(), vpn_encrypt::CryptoError>(())
+//! ```
+
+#![warn(missing_docs)]
+
+pub mod aad;
+mod batch;
+pub mod compression;
+mod engine;
+mod error;
+pub mod format;
+pub mod handshake;
+pub mod kdf;
+mod key;
+pub mod keyfile;
+mod keyring;
+pub mod nonce;
+pub mod padding;
+pub mod rekey;
+mod replay;
+pub mod session;
+pub mod stream;
+pub mod suite;
+pub mod transport;
+#[cfg(target_os = "linux")]
+pub mod tun;
+
+pub use engine::{CryptoEngine, KEY_LEN, NONCE_LEN, TAG_LEN};
+pub use error::CryptoError;
+pub use key::SecretKey;
+pub use keyring::Keyring;
+pub use nonce::generate_nonce;
+pub use replay::{ReplayWindow, WINDOW_SIZE};
@@ -1,200 +1,333 @@
-use chacha20poly1305::{
-    aead::{Aead, KeyInit, Nonce, Payload}, Key, XChaCha20Poly1305
-};
-use rand::{rngs::OsRng, RngCore};
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
 
-struct CryptoEngine {
-    cipher: XChaCha20Poly1305,
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use vpn_encrypt::format::{self, CipherSuite, HEADER_LEN, Header};
+use vpn_encrypt::kdf::Argon2Params;
+use vpn_encrypt::keyfile::{KeyFile, KeyUsage};
+use vpn_encrypt::{NONCE_LEN, TAG_LEN};
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// Encrypt, decrypt and inspect VPN records.
+#[derive(Parser)]
+#[command(name = "vpn-encrypt", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Write a new random key file, readable only by its owner.
+    Keygen {
+        /// Path of the key file to create.
+        output: PathBuf,
+        /// Overwrite the file if it already exists.
+        #[arg(long)]
+        force: bool,
+        /// Cipher suite the key seals records with.
+        #[arg(long, value_enum, default_value_t = Suite::Xchacha20poly1305)]
+        suite: Suite,
+        /// Key id written into record headers.
+        #[arg(long, default_value_t = 0)]
+        key_id: u32,
+        /// Intended usage recorded in the key file.
+        #[arg(long, value_enum, default_value_t = Usage::Any)]
+        usage: Usage,
+        /// Protect the key with the passphrase in this environment variable.
+        #[arg(long, value_name = "VAR")]
+        passphrase_env: Option<String>,
+    },
+    /// Encrypt a payload into a record.
+    Encrypt {
+        #[command(flatten)]
+        common: CryptoArgs,
+    },
+    /// Decrypt a record back into its payload.
+    Decrypt {
+        #[command(flatten)]
+        common: CryptoArgs,
+    },
+    /// Print a record's header fields without decrypting it.
+    Inspect {
+        #[command(flatten)]
+        io: IoArgs,
+    },
+}
+
+#[derive(Args)]
+struct CryptoArgs {
+    /// Key file written by `keygen`; it sets the suite and key id.
+    #[arg(short, long)]
+    key: PathBuf,
+    /// Environment variable holding the key file's passphrase.
+    #[arg(long, value_name = "VAR")]
+    passphrase_env: Option<String>,
+    /// Additional authenticated data bound to the record.
+    #[arg(long, default_value = "")]
+    aad: String,
+    #[command(flatten)]
+    io: IoArgs,
+}
+
+#[derive(Args)]
+struct IoArgs {
+    /// Read from this file instead of stdin.
+    #[arg(short, long)]
+    input: Option<PathBuf>,
+    /// Write to this file instead of stdout.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Encoding of the record; payloads are always raw bytes.
+    #[arg(short, long, value_enum, default_value_t = Encoding::Raw)]
+    format: Encoding,
 }
 
-impl CryptoEngine {
-    fn new(key : &[u8;32]) -> Self {
-            let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
-            Self { cipher }
+#[derive(Clone, Copy, ValueEnum)]
+enum Encoding {
+    Raw,
+    Hex,
+    Base64,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Suite {
+    Xchacha20poly1305,
+    Chacha20poly1305,
+    Aes256gcm,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Usage {
+    Any,
+    Records,
+    Streams,
+    Sessions,
+}
+
+impl From<Usage> for KeyUsage {
+    fn from(usage: Usage) -> Self {
+        match usage {
+            Usage::Any => KeyUsage::Any,
+            Usage::Records => KeyUsage::Records,
+            Usage::Streams => KeyUsage::Streams,
+            Usage::Sessions => KeyUsage::Sessions,
+        }
     }
-    fn encrypt (&self, message : &str, AAD : &str) -> Result<Vec<u8>, &'static str>{
-        // Validate input: check for empty message
-        if message.is_empty() {
-            return Err("Message cannot be empty");
+}
+
+impl From<Suite> for CipherSuite {
+    fn from(suite: Suite) -> Self {
+        match suite {
+            Suite::Xchacha20poly1305 => CipherSuite::XChaCha20Poly1305,
+            Suite::Chacha20poly1305 => CipherSuite::ChaCha20Poly1305,
+            Suite::Aes256gcm => CipherSuite::Aes256Gcm,
         }
-        
-        let nonce = generate_nonce();
-        let payload = Payload {
-            msg: message.as_bytes(),
-            aad: AAD.as_bytes(),
-        };
-        // Use self.cipher instead of CryptoEngine::cipher
-        let ciphertext = self.cipher.encrypt(&nonce.into(), payload)
-            .map_err(|_| "Encryption failed")?;
-        
-        // Combine nonce and ciphertext as requested
-        let mut result = nonce.to_vec();
-        result.extend_from_slice(&ciphertext);
-        Ok(result)
     }
-    fn decrypt (&self, data : &[u8], AAD : &str) -> Result<String, &'static str>{
-        // Validate input: ensure data has minimum length for nonce + ciphertext
-        if data.len() < 24 {
-            return Err("Invalid data: must be at least 24 bytes (nonce + ciphertext)");
+}
+
+impl Encoding {
+    fn encode(self, record: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Raw => record.to_vec(),
+            Encoding::Hex => format!("{}\n", hex::encode(record)).into_bytes(),
+            Encoding::Base64 => format!("{}\n", BASE64.encode(record)).into_bytes(),
         }
-        
-        let nonce = &data[..24];
-        let ciphertext = &data[24..];
-        
-        // Additional validation: ensure there's actual ciphertext beyond the nonce
-        if ciphertext.is_empty() {
-            return Err("Invalid data: no ciphertext found after nonce");
+    }
+
+    fn decode(self, input: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            Encoding::Raw => input.to_vec(),
+            Encoding::Hex => hex::decode(input.trim_ascii())?,
+            Encoding::Base64 => BASE64.decode(input.trim_ascii())?,
+        })
+    }
+}
+
+impl IoArgs {
+    fn read(&self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        match &self.input {
+            Some(path) => File::open(path)?.read_to_end(&mut data)?,
+            None => io::stdin().read_to_end(&mut data)?,
+        };
+        Ok(data)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<()> {
+        match &self.output {
+            Some(path) => fs::write(path, data)?,
+            None => io::stdout().write_all(data)?,
         }
-        
-        let payload = Payload {
-            msg: ciphertext,
-            aad: AAD.as_bytes(),
+        Ok(())
+    }
+
+    /// Like [`write`](Self::write), for plaintext: a new output file is
+    /// created readable by the owner only on unix.
+    fn write_private(&self, data: &[u8]) -> Result<()> {
+        let Some(path) = &self.output else {
+            return self.write(data);
         };
-        let plaintext = self.cipher.decrypt(nonce.into(), payload)
-            .map_err(|_| "Decryption failed: invalid ciphertext or wrong AAD")?;
-        
-        // Convert to UTF-8 with specific error message
-        String::from_utf8(plaintext)
-            .map_err(|_| "Decryption succeeded but result is not valid UTF-8")
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(path)?.write_all(data)?;
+        Ok(())
     }
 }
 
-fn generate_nonce() -> [u8;24] {
-    let mut nonce = [0;24];
-    OsRng.fill_bytes(&mut nonce);
-    nonce
-}
-
-
-
-
-fn main() {
-    println!("=== VPN Encryption Testing ===\n");
-    
-    // Create a placeholder key (in production, use proper key derivation)
-    let key = [0u8; 32];
-    let engine = CryptoEngine::new(&key);
-    
-    // Test data
-    let message = "Hello, VPN!";
-    let aad = "vpn-auth";
-    
-    // Test 1: Normal Encryption/Decryption
-    println!("1. Testing Normal Encryption/Decryption:");
-    println!("   Message: \"{}\"", message);
-    println!("   AAD: \"{}\"", aad);
-    
-    match engine.encrypt(message, aad) {
-        Ok(encrypted_data) => {
-            println!("Encryption successful!");
-            println!("Encrypted data length: {} bytes", encrypted_data.len());
-            println!("First 10 bytes: {:?}", &encrypted_data[..10.min(encrypted_data.len())]);
-            
-            // Test normal decryption
-            match engine.decrypt(&encrypted_data, aad) {
-                Ok(decrypted_message) => {
-                    println!("Decryption successful!");
-                    println!("Decrypted message: \"{}\"", decrypted_message);
-                    println!("Match original: {}", decrypted_message == message);
-                }
-                Err(e) => {
-                    println!("Unexpected decryption failure: {}", e);
-                }
-            }
-            
-            // Test 2: Error Handling - Empty Message
-            println!("\n2. Testing Error Handling - Empty Message:");
-            match engine.encrypt("", aad) {
-                Ok(_) => println!("Unexpected success with empty message"),
-                Err(e) => println!("Expected error: {}", e),
-            }
-            
-            // Test 3: Error Handling - Invalid Data Length
-            println!("\n3. Testing Error Handling - Invalid Data Length:");
-            let short_data = vec![1, 2, 3]; // Only 3 bytes, need at least 24
-            match engine.decrypt(&short_data, aad) {
-                Ok(_) => println!("Unexpected success with short data"),
-                Err(e) => println!("Expected error: {}", e),
-            }
-            
-            // Test 4: Error Handling - Data with only nonce (no ciphertext)
-            println!("\n4. Testing Error Handling - Nonce Only (No Ciphertext):");
-            let nonce_only = vec![0u8; 24]; // Exactly 24 bytes (nonce only)
-            match engine.decrypt(&nonce_only, aad) {
-                Ok(_) => println!("Unexpected success with nonce-only data"),
-                Err(e) => println!("Expected error: {}", e),
-            }
-            
-            // Test 5: AAD Variations - Wrong AAD
-            println!("\n5. Testing AAD Variations - Wrong AAD:");
-            let wrong_aad = "wrong-auth";
-            println!("Original AAD: \"{}\"", aad);
-            println!("Testing with: \"{}\"", wrong_aad);
-            match engine.decrypt(&encrypted_data, wrong_aad) {
-                Ok(decrypted_message) => {
-                    println!("Unexpected success: \"{}\"", decrypted_message);
-                }
-                Err(e) => {
-                    println!("Expected authentication failure: {}", e);
-                    println!("This confirms AAD authentication is working properly!");
-                }
-            }
-            
-            // Test 6: AAD Variations - Multiple Wrong AADs
-            println!("\n6. Testing Multiple Wrong AAD Values:");
-            let wrong_aads = vec!["", "vpn", "vpn-auth-wrong", "123", "VPN-AUTH"];
-            for (i, test_aad) in wrong_aads.iter().enumerate() {
-                println!("   Test {}: AAD = \"{}\"", i + 1, test_aad);
-                match engine.decrypt(&encrypted_data, test_aad) {
-                    Ok(_) => println!("Unexpected success!"),
-                    Err(e) => println!("Failed as expected: {}", e),
-                }
-            }
-            
-            // Test 7: AAD Variations - Case Sensitivity
-            println!("\n7. Testing AAD Case Sensitivity:");
-            let case_variations = vec!["VPN-AUTH", "vpn-AUTH", "Vpn-Auth"];
-            for case_aad in case_variations {
-                println!("Testing AAD: \"{}\"", case_aad);
-                match engine.decrypt(&encrypted_data, case_aad) {
-                    Ok(_) => println!("Unexpected success - AAD should be case sensitive!"),
-                    Err(e) => println!("Correctly rejected: {}", e),
-                }
-            }
-            
-        }
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
         Err(e) => {
-            println!("Initial encryption failed: {}", e);
+            eprintln!("vpn-encrypt: {}", e);
+            ExitCode::FAILURE
         }
     }
-    
-    // Test 8: Edge Cases - Different Message Lengths
-    println!("\n8. Testing Different Message Lengths:");
-    let test_messages = vec![
-        ("a", "Single character"),
-        ("Hello, VPN World! This is a longer message to test encryption.", "Long message"),
-        ("🔒🔑💻", "Unicode characters"),
-    ];
-    
-    for (test_msg, description) in test_messages {
-        println!("   Testing {}: \"{}\"", description, test_msg);
-        match engine.encrypt(test_msg, aad) {
-            Ok(encrypted) => {
-                match engine.decrypt(&encrypted, aad) {
-                    Ok(decrypted) => {
-                        if decrypted == test_msg {
-                            println!("Success: matches original");
-                        } else {
-                            println!("Error: doesn't match original");
-                        }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    match cli.command {
+        Command::Keygen {
+            output,
+            force,
+            suite,
+            key_id,
+            usage,
+            passphrase_env,
+        } => {
+            let passphrase = passphrase_env.map(|var| passphrase(&var)).transpose()?;
+            if !force {
+                // Claim the path atomically so an existing key is never replaced.
+                let mut options = OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                options.open(&output).map_err(|e| match e.kind() {
+                    io::ErrorKind::AlreadyExists => {
+                        format!("{}: already exists (use --force)", output.display())
                     }
-                    Err(e) => println!("Decryption failed: {}", e),
+                    _ => format!("{}: {}", output.display(), e),
+                })?;
+            }
+            let key_file = KeyFile::generate()
+                .with_suite(suite.into())
+                .with_usage(usage.into())
+                .with_key_id(key_id);
+            let result = match &passphrase {
+                Some(passphrase) => key_file.save_with_passphrase(
+                    &output,
+                    passphrase.as_bytes(),
+                    &Argon2Params::default(),
+                ),
+                None => key_file.save(&output),
+            };
+            if let Err(e) = result {
+                if !force {
+                    let _ = fs::remove_file(&output);
                 }
+                return Err(format!("{}: {}", output.display(), e).into());
             }
-            Err(e) => println!("Encryption failed: {}", e),
+            Ok(())
         }
+        Command::Encrypt { common } => {
+            let engine = load_key(&common)?.engine();
+            let record = engine.encrypt_bytes(&common.io.read()?, common.aad.as_bytes())?;
+            common.io.write(&common.io.format.encode(&record))
+        }
+        Command::Decrypt { common } => {
+            let engine = load_key(&common)?.engine();
+            let record = common.io.format.decode(&common.io.read()?)?;
+            let payload = engine.decrypt_bytes(&record, common.aad.as_bytes())?;
+            common.io.write_private(&payload)
+        }
+        Command::Inspect { io } => {
+            let record = io.format.decode(&io.read()?)?;
+            io.write(inspect(&record)?.as_bytes())
+        }
+    }
+}
+
+fn passphrase(var: &str) -> Result<String> {
+    std::env::var(var).map_err(|e| format!("{}: {}", var, e).into())
+}
+
+fn load_key(args: &CryptoArgs) -> Result<KeyFile> {
+    let path = &args.key;
+    match &args.passphrase_env {
+        Some(var) => KeyFile::load_with_passphrase(path, passphrase(var)?.as_bytes()),
+        None => KeyFile::load(path),
+    }
+    .map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// Describes a record's framing without needing its key.
+fn inspect(record: &[u8]) -> Result<String> {
+    let mut out = String::new();
+    if !format::has_header(record) {
+        let nonce_end = record.len().min(NONCE_LEN);
+        out += "format:     legacy (no header)\n";
+        out += &format!("nonce:      {}\n", hex::encode(&record[..nonce_end]));
+        out += &format!("ciphertext: {} bytes\n", record.len() - nonce_end);
+        return Ok(out);
+    }
+
+    let header = Header::parse(record)?;
+    let nonce_end = (HEADER_LEN + header.suite.nonce_len()).min(record.len());
+    let ciphertext_len = record.len() - nonce_end;
+    let mut flags = format!("{:#06x}", header.flags);
+    let names: Vec<&str> = [
+        (format::FLAG_COUNTER_NONCE, "counter nonce"),
+        (format::FLAG_PADDED, "padded"),
+        (format::FLAG_LZ4, "lz4"),
+        (format::FLAG_ZSTD, "zstd"),
+    ]
+    .into_iter()
+    .filter(|(flag, _)| header.flags & flag != 0)
+    .map(|(_, name)| name)
+    .collect();
+    if !names.is_empty() {
+        flags += &format!(" ({})", names.join(", "));
+    }
+
+    out += &format!("format:     headered (version {})\n", header.version);
+    out += &format!(
+        "suite:      {:?} (id {})\n",
+        header.suite,
+        header.suite.id()
+    );
+    out += &format!("flags:      {}\n", flags);
+    out += &format!("key id:     {}\n", header.key_id);
+    out += &format!(
+        "nonce:      {}\n",
+        hex::encode(&record[HEADER_LEN..nonce_end])
+    );
+    match format::sequence_number(record) {
+        Ok(Some(sequence)) => out += &format!("sequence:   {}\n", sequence),
+        Ok(None) => {}
+        Err(_) => out += "sequence:   (truncated)\n",
     }
-    
-    println!("\n=== All Tests Complete ===");
-    println!("Summary: Tested encryption, decryption, error handling, and AAD authentication");
+    // Padding and compression happen before sealing, so the payload size
+    // cannot be told without the key.
+    let sealed_flags = format::FLAG_PADDED | format::FLAG_LZ4 | format::FLAG_ZSTD;
+    let body = if header.flags & sealed_flags != 0 {
+        "sealed bytes"
+    } else {
+        "payload"
+    };
+    out += &format!(
+        "ciphertext: {} bytes ({} {} + {}-byte tag)\n",
+        ciphertext_len,
+        ciphertext_len.saturating_sub(TAG_LEN),
+        body,
+        TAG_LEN
+    );
+    Ok(out)
 }
@@ -0,0 +1,231 @@
+//! Nonce generation strategies.
+//!
+//! By default every record gets 24 fresh random bytes from the OS RNG. For
+//! the data plane an engine can instead use [`CounterNonce`]: a random
+//! per-session prefix followed by a 64-bit big-endian counter. That avoids a
+//! syscall per packet, guarantees uniqueness without relying on the RNG, and
+//! lets receivers read a sequence number out of each record.
+//!
+//! Nonces are as long as the engine's cipher suite needs: the counter always
+//! takes the last 8 bytes and the prefix fills the rest (16 bytes for
+//! XChaCha20-Poly1305, 4 bytes for the 12-byte-nonce suites).
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rand::{RngCore, rngs::OsRng};
+
+use crate::engine::NONCE_LEN;
+use crate::error::CryptoError;
+use crate::format::FLAG_COUNTER_NONCE;
+
+/// Length of the counter at the end of a counter nonce.
+pub const COUNTER_LEN: usize = 8;
+
+/// Maximum length of the random session prefix in a counter nonce.
+pub const PREFIX_LEN: usize = NONCE_LEN - COUNTER_LEN;
+
+/// Draws a fresh random 24-byte nonce from the operating system RNG.
+pub fn generate_nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+/// Nonces built from a fixed random prefix and a monotonic counter.
+///
+/// Layout: `[prefix][8-byte big-endian counter]`, where the prefix is the
+/// first `nonce_len - 8` bytes of the session prefix. The counter starts
+/// at zero and the source refuses to hand out a nonce once it would wrap, so
+/// at most `2^64 - 1` records can be sealed per prefix.
+///
+/// Uniqueness across sources rests on the random prefix alone, which is only
+/// 4 bytes for the 12-byte-nonce suites, so give each source its own key; see
+/// [`CryptoEngine::with_counter_nonces`](crate::CryptoEngine::with_counter_nonces).
+#[derive(Debug)]
+pub struct CounterNonce {
+    prefix: [u8; PREFIX_LEN],
+    next: AtomicU64,
+}
+
+impl CounterNonce {
+    /// Creates a counter source with a fresh random prefix.
+    pub fn new() -> Self {
+        let mut prefix = [0u8; PREFIX_LEN];
+        OsRng.fill_bytes(&mut prefix);
+        Self::with_prefix(prefix, 0)
+    }
+
+    /// Creates a counter source with an explicit prefix and starting counter.
+    pub fn with_prefix(prefix: [u8; PREFIX_LEN], start: u64) -> Self {
+        Self {
+            prefix,
+            next: AtomicU64::new(start),
+        }
+    }
+
+    /// Returns the counter value the next nonce will carry.
+    pub fn peek(&self) -> u64 {
+        self.next.load(Ordering::Relaxed)
+    }
+
+    /// Returns the next 24-byte nonce, or [`CryptoError::NonceExhausted`]
+    /// once the counter would wrap.
+    pub fn next_nonce(&self) -> Result<[u8; NONCE_LEN], CryptoError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.fill_nonce(&mut nonce)?;
+        Ok(nonce)
+    }
+
+    /// Writes the next nonce into `out`, which must be between 8 and 24
+    /// bytes long.
+    pub fn fill_nonce(&self, out: &mut [u8]) -> Result<(), CryptoError> {
+        let counter = self.reserve(1)?;
+        self.write_nonce(out, counter);
+        Ok(())
+    }
+
+    /// Writes `out.len() / nonce_len` consecutive nonces into `out` with a
+    /// single atomic update. Either all of them are handed out or none.
+    pub fn fill_nonces(&self, out: &mut [u8], nonce_len: usize) -> Result<(), CryptoError> {
+        let count = (out.len() / nonce_len) as u64;
+        let first = self.reserve(count)?;
+        for (i, nonce) in out.chunks_exact_mut(nonce_len).enumerate() {
+            self.write_nonce(nonce, first + i as u64);
+        }
+        Ok(())
+    }
+
+    /// Claims `count` counter values and returns the first. The last value
+    /// handed out is always below `u64::MAX`, so the counter never wraps.
+    fn reserve(&self, count: u64) -> Result<u64, CryptoError> {
+        self.next
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                n.checked_add(count)
+            })
+            .map_err(|_| CryptoError::NonceExhausted)
+    }
+
+    fn write_nonce(&self, out: &mut [u8], counter: u64) {
+        let (prefix, tail) = out.split_at_mut(out.len() - COUNTER_LEN);
+        prefix.copy_from_slice(&self.prefix[..prefix.len()]);
+        tail.copy_from_slice(&counter.to_be_bytes());
+    }
+}
+
+impl Default for CounterNonce {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Extracts the counter from a nonce produced by [`CounterNonce`].
+///
+/// The nonce must be at least 8 bytes long.
+pub fn counter_of(nonce: &[u8]) -> u64 {
+    let mut counter = [0u8; COUNTER_LEN];
+    counter.copy_from_slice(&nonce[nonce.len() - COUNTER_LEN..]);
+    u64::from_be_bytes(counter)
+}
+
+/// How an engine chooses the nonce for each record.
+#[derive(Debug, Default)]
+pub(crate) enum NonceSource {
+    /// `nonce_len` random bytes per record.
+    #[default]
+    Random,
+    /// Random prefix plus monotonic counter.
+    Counter(CounterNonce),
+}
+
+impl NonceSource {
+    pub(crate) fn fill_nonce(&self, out: &mut [u8]) -> Result<(), CryptoError> {
+        match self {
+            NonceSource::Random => {
+                OsRng.fill_bytes(out);
+                Ok(())
+            }
+            NonceSource::Counter(counter) => counter.fill_nonce(out),
+        }
+    }
+
+    /// Fills `out` with back-to-back nonces of `nonce_len` bytes: one RNG
+    /// call or one counter reservation for the whole run.
+    pub(crate) fn fill_nonces(&self, out: &mut [u8], nonce_len: usize) -> Result<(), CryptoError> {
+        match self {
+            NonceSource::Random => {
+                OsRng.fill_bytes(out);
+                Ok(())
+            }
+            NonceSource::Counter(counter) => counter.fill_nonces(out, nonce_len),
+        }
+    }
+
+    /// Header flags advertising how the nonce was built.
+    pub(crate) fn header_flags(&self) -> u16 {
+        match self {
+            NonceSource::Random => 0,
+            NonceSource::Counter(_) => FLAG_COUNTER_NONCE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counter_nonces_increment() {
+        let source = CounterNonce::with_prefix([7u8; PREFIX_LEN], 0);
+        let first = source.next_nonce().unwrap();
+        let second = source.next_nonce().unwrap();
+        assert_eq!(&first[..PREFIX_LEN], &[7u8; PREFIX_LEN]);
+        assert_eq!(counter_of(&first), 0);
+        assert_eq!(counter_of(&second), 1);
+        assert_eq!(source.peek(), 2);
+    }
+
+    #[test]
+    fn counter_refuses_to_wrap() {
+        let source = CounterNonce::with_prefix([0u8; PREFIX_LEN], u64::MAX - 1);
+        assert_eq!(counter_of(&source.next_nonce().unwrap()), u64::MAX - 1);
+        assert_eq!(source.next_nonce(), Err(CryptoError::NonceExhausted));
+        assert_eq!(source.next_nonce(), Err(CryptoError::NonceExhausted));
+    }
+
+    #[test]
+    fn short_nonces_truncate_prefix() {
+        let source = CounterNonce::with_prefix([7u8; PREFIX_LEN], 5);
+        let mut nonce = [0u8; 12];
+        source.fill_nonce(&mut nonce).unwrap();
+        assert_eq!(&nonce[..4], &[7u8; 4]);
+        assert_eq!(counter_of(&nonce), 5);
+    }
+
+    #[test]
+    fn batch_reservation_is_contiguous() {
+        let source = CounterNonce::with_prefix([7u8; PREFIX_LEN], 10);
+        let mut nonces = [0u8; 12 * 3];
+        source.fill_nonces(&mut nonces, 12).unwrap();
+        let counters: Vec<u64> = nonces.chunks_exact(12).map(counter_of).collect();
+        assert_eq!(counters, [10, 11, 12]);
+        assert_eq!(source.peek(), 13);
+    }
+
+    #[test]
+    fn batch_reservation_is_all_or_nothing() {
+        let source = CounterNonce::with_prefix([0u8; PREFIX_LEN], u64::MAX - 2);
+        let mut nonces = [0u8; 24 * 3];
+        assert_eq!(
+            source.fill_nonces(&mut nonces, 24),
+            Err(CryptoError::NonceExhausted)
+        );
+        assert_eq!(source.peek(), u64::MAX - 2);
+    }
+
+    #[test]
+    fn random_prefixes_differ() {
+        let a = CounterNonce::new().next_nonce().unwrap();
+        let b = CounterNonce::new().next_nonce().unwrap();
+        assert_ne!(a[..PREFIX_LEN], b[..PREFIX_LEN]);
+    }
+}
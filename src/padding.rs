@@ -0,0 +1,119 @@
+//! Length-hiding padding.
+//!
+//! Without padding a record is exactly 40 bytes (with the default suite)
+//! longer than its message, so an on-path observer learns every payload
+//! size. An engine with a [`Padding`] policy (see
+//! [`CryptoEngine::with_padding`](crate::CryptoEngine::with_padding)) pads
+//! the message before sealing it, so the padding is encrypted and
+//! authenticated with the message and only the padded length shows on the
+//! wire.
+//!
+//! Padding is a single `0x80` byte followed by zeros (ISO/IEC 7816-4), and
+//! padded records set [`FLAG_PADDED`](crate::format::FLAG_PADDED) in their
+//! header. Receivers strip it whenever the flag is set, whatever their own
+//! policy, and messages ending in zero bytes come back intact.
+
+use crate::error::CryptoError;
+
+/// First byte of the padding; everything after it is zero.
+pub const MARKER: u8 = 0x80;
+
+/// How far an engine pads the messages it seals.
+///
+/// Every padded message grows by at least one byte, for the marker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Padding {
+    /// Pads the plaintext to a multiple of this many bytes.
+    Multiple(usize),
+    /// Pads the plaintext to the smallest of these lengths it fits in.
+    /// Longer plaintexts are padded to a multiple of the largest bucket.
+    Buckets(Vec<usize>),
+    /// Pads whole records to this many bytes, e.g. the path MTU minus the
+    /// IP and UDP headers. Messages too long for it only get the marker.
+    Mtu(usize),
+}
+
+impl Padding {
+    /// Returns the padded plaintext length for a message of `message_len`
+    /// bytes, in a record that adds `overhead` bytes of header, nonce and
+    /// tag.
+    pub fn padded_len(&self, message_len: usize, overhead: usize) -> usize {
+        let min = message_len + 1;
+        match self {
+            Padding::Multiple(n) => min.next_multiple_of((*n).max(1)),
+            Padding::Buckets(buckets) => {
+                match buckets.iter().copied().filter(|&b| b >= min).min() {
+                    Some(bucket) => bucket,
+                    None => match buckets.iter().max() {
+                        Some(&largest) => min.next_multiple_of(largest.max(1)),
+                        None => min,
+                    },
+                }
+            }
+            Padding::Mtu(mtu) => mtu.saturating_sub(overhead).max(min),
+        }
+    }
+}
+
+/// Writes the padding into `padded[message_len..]`.
+pub(crate) fn pad(padded: &mut [u8], message_len: usize) {
+    padded[message_len] = MARKER;
+    padded[message_len + 1..].fill(0);
+}
+
+/// Returns the length of the message in front of the padding.
+pub(crate) fn unpadded_len(padded: &[u8]) -> Result<usize, CryptoError> {
+    match padded.iter().rposition(|&b| b != 0) {
+        Some(end) if padded[end] == MARKER => Ok(end),
+        _ => Err(CryptoError::InvalidPadding),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multiple_rounds_up_past_the_marker() {
+        let padding = Padding::Multiple(16);
+        assert_eq!(padding.padded_len(0, 40), 16);
+        assert_eq!(padding.padded_len(15, 40), 16);
+        assert_eq!(padding.padded_len(16, 40), 32);
+        assert_eq!(Padding::Multiple(0).padded_len(5, 40), 6);
+    }
+
+    #[test]
+    fn buckets_pick_smallest_fit() {
+        let padding = Padding::Buckets(vec![1280, 128, 512]);
+        assert_eq!(padding.padded_len(0, 40), 128);
+        assert_eq!(padding.padded_len(127, 40), 128);
+        assert_eq!(padding.padded_len(128, 40), 512);
+        assert_eq!(padding.padded_len(1300, 40), 2560);
+        assert_eq!(Padding::Buckets(Vec::new()).padded_len(5, 40), 6);
+    }
+
+    #[test]
+    fn mtu_fills_the_record() {
+        let padding = Padding::Mtu(1420);
+        assert_eq!(padding.padded_len(0, 52), 1368);
+        assert_eq!(padding.padded_len(1367, 52), 1368);
+        assert_eq!(padding.padded_len(1400, 52), 1401);
+    }
+
+    #[test]
+    fn pad_round_trip_keeps_trailing_zeros() {
+        let message = [1, 0x80, 0, 0];
+        let mut padded = [0xff; 16];
+        padded[..4].copy_from_slice(&message);
+        pad(&mut padded, message.len());
+        assert_eq!(&padded[4..6], &[MARKER, 0]);
+        assert_eq!(unpadded_len(&padded), Ok(message.len()));
+    }
+
+    #[test]
+    fn unpad_rejects_missing_marker() {
+        assert_eq!(unpadded_len(&[0; 8]), Err(CryptoError::InvalidPadding));
+        assert_eq!(unpadded_len(&[1, 2, 0]), Err(CryptoError::InvalidPadding));
+        assert_eq!(unpadded_len(&[]), Err(CryptoError::InvalidPadding));
+    }
+}
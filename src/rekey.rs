@@ -0,0 +1,472 @@
+//! Sessions that replace their keys as they are used.
+//!
+//! A [`RekeyingSession`] works like a [`Session`](crate::session::Session)
+//! but counts the messages and bytes it sends and the age of its sending
+//! key. Once any limit in its [`RekeyPolicy`] is reached, the next key is
+//! derived through a one-way HKDF ratchet:
+//!
+//! ```text
+//! chain[0]     = HKDF(shared secret, direction label)
+//! key[n]       = HKDF(chain[n], "vpn-encrypt rekey key")
+//! chain[n + 1] = HKDF(chain[n], "vpn-encrypt rekey chain")
+//! ```
+//!
+//! Each chain step overwrites the previous chain key, so a key stolen later
+//! cannot be used to recompute earlier ones. The epoch `n` travels in the
+//! key id field of the record header, which is how the peer learns about a
+//! rekey: when a record names a newer epoch, the receiver ratchets forward
+//! to it, and keeps the previous epoch's key for
+//! [`overlap`](RekeyPolicy::overlap) so packets still in flight decrypt.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use crate::engine::CryptoEngine;
+use crate::error::CryptoError;
+use crate::format::Header;
+use crate::handshake::TransportKeys;
+use crate::kdf;
+use crate::key::SecretKey;
+use crate::session::Role;
+
+const KEY_LABEL: &[u8] = b"vpn-encrypt rekey key";
+const CHAIN_LABEL: &[u8] = b"vpn-encrypt rekey chain";
+
+/// How many epochs a receiver ratchets forward in one step. Records naming
+/// an epoch further ahead are rejected rather than costing unbounded work.
+pub const MAX_EPOCH_SKIP: u32 = 16;
+
+/// Limits after which a [`RekeyingSession`] moves to a new sending key.
+///
+/// `None` disables a limit. The key changes as soon as any enabled limit is
+/// reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RekeyPolicy {
+    /// Messages sent under one key.
+    pub max_messages: Option<u64>,
+    /// Plaintext bytes sent under one key.
+    pub max_bytes: Option<u64>,
+    /// Age of the sending key.
+    pub max_age: Option<Duration>,
+    /// How long the previous receiving key is still accepted after the peer
+    /// moves to a new one.
+    pub overlap: Duration,
+}
+
+impl Default for RekeyPolicy {
+    /// WireGuard's two-minute rekey interval, with generous message and
+    /// byte limits and a 30-second overlap.
+    fn default() -> Self {
+        Self {
+            max_messages: Some(1 << 32),
+            max_bytes: Some(1 << 40),
+            max_age: Some(Duration::from_secs(120)),
+            overlap: Duration::from_secs(30),
+        }
+    }
+}
+
+/// One direction's ratchet position.
+struct Chain {
+    key: SecretKey,
+    epoch: u32,
+    label: &'static [u8],
+}
+
+impl Chain {
+    fn new(secret: &[u8], label: &'static [u8]) -> Result<Self, CryptoError> {
+        Ok(Self {
+            key: kdf::derive_from_secret(secret, None, label)?,
+            epoch: 0,
+            label,
+        })
+    }
+
+    /// Builds the engine for the current epoch.
+    fn engine(&self) -> Result<CryptoEngine, CryptoError> {
+        let key = kdf::derive_from_secret(self.key.as_bytes(), None, KEY_LABEL)?;
+        Ok(CryptoEngine::new(&key)
+            .with_key_id(self.epoch)
+            .with_aad_label(self.label))
+    }
+
+    /// Moves to the next epoch, discarding the current chain key.
+    fn advance(&mut self) -> Result<(), CryptoError> {
+        let epoch = self
+            .epoch
+            .checked_add(1)
+            .ok_or(CryptoError::NonceExhausted)?;
+        self.key = kdf::derive_from_secret(self.key.as_bytes(), None, CHAIN_LABEL)?;
+        self.epoch = epoch;
+        Ok(())
+    }
+
+    fn duplicate(&self) -> Self {
+        Self {
+            key: SecretKey::from(*self.key.as_bytes()),
+            epoch: self.epoch,
+            label: self.label,
+        }
+    }
+}
+
+struct Previous {
+    engine: CryptoEngine,
+    expires: Instant,
+}
+
+/// A two-way session that rekeys according to a [`RekeyPolicy`].
+///
+/// Like [`Session`](crate::session::Session), each direction has its own
+/// key chain and AAD label, sending uses counter nonces and receiving
+/// rejects replays (per epoch).
+pub struct RekeyingSession {
+    role: Role,
+    policy: RekeyPolicy,
+    send_chain: Chain,
+    send: CryptoEngine,
+    sent_messages: u64,
+    sent_bytes: u64,
+    send_started: Instant,
+    receive_chain: Chain,
+    receive: CryptoEngine,
+    previous: Option<Previous>,
+}
+
+impl RekeyingSession {
+    /// Derives both directions' key chains from a secret shared by the two
+    /// peers. Both peers call this with the same secret and opposite roles.
+    pub fn from_secret(
+        secret: &[u8],
+        role: Role,
+        policy: RekeyPolicy,
+    ) -> Result<Self, CryptoError> {
+        let send_chain = Chain::new(secret, role.send_label())?;
+        let receive_chain = Chain::new(secret, role.peer().send_label())?;
+        Ok(Self {
+            role,
+            policy,
+            send: send_chain.engine()?.with_counter_nonces(),
+            send_chain,
+            sent_messages: 0,
+            sent_bytes: 0,
+            send_started: Instant::now(),
+            receive: receive_chain.engine()?.with_replay_protection(),
+            receive_chain,
+            previous: None,
+        })
+    }
+
+    /// Builds a session from the output of a completed
+    /// [`Handshake`](crate::handshake::Handshake).
+    ///
+    /// The key chains start from
+    /// [`rekey_secret`](TransportKeys::rekey_secret); the handshake's own
+    /// `send` and `receive` engines are not used.
+    pub fn from_handshake(keys: TransportKeys, policy: RekeyPolicy) -> Result<Self, CryptoError> {
+        Self::from_secret(keys.rekey_secret.as_bytes(), keys.role, policy)
+    }
+
+    /// Returns this peer's role.
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Returns the rekey policy.
+    pub fn policy(&self) -> &RekeyPolicy {
+        &self.policy
+    }
+
+    /// Returns the epoch of the current sending key.
+    pub fn send_epoch(&self) -> u32 {
+        self.send_chain.epoch
+    }
+
+    /// Returns the newest epoch received from the peer.
+    pub fn receive_epoch(&self) -> u32 {
+        self.receive_chain.epoch
+    }
+
+    /// Returns `true` if sending `message_len` more bytes would exceed the
+    /// policy, so the next [`encrypt`](Self::encrypt) will rekey first.
+    ///
+    /// A key that has not sent anything yet is never replaced.
+    pub fn needs_rekey(&self, message_len: usize) -> bool {
+        if self.sent_messages == 0 {
+            return false;
+        }
+        let policy = &self.policy;
+        policy
+            .max_messages
+            .is_some_and(|max| self.sent_messages >= max)
+            || policy
+                .max_bytes
+                .is_some_and(|max| self.sent_bytes.saturating_add(message_len as u64) > max)
+            || policy
+                .max_age
+                .is_some_and(|max| self.send_started.elapsed() >= max)
+    }
+
+    /// Moves to the next sending key now, regardless of the policy.
+    pub fn rekey(&mut self) -> Result<(), CryptoError> {
+        let mut chain = self.send_chain.duplicate();
+        chain.advance()?;
+        self.send = chain.engine()?.with_counter_nonces();
+        self.send_chain = chain;
+        self.sent_messages = 0;
+        self.sent_bytes = 0;
+        self.send_started = Instant::now();
+        Ok(())
+    }
+
+    /// Encrypts a message for the peer, rekeying first if the policy says so.
+    pub fn encrypt(&mut self, message: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if self.needs_rekey(message.len()) {
+            self.rekey()?;
+        }
+        let record = self.send.encrypt_bytes(message, aad)?;
+        self.sent_messages += 1;
+        self.sent_bytes = self.sent_bytes.saturating_add(message.len() as u64);
+        Ok(record)
+    }
+
+    /// Decrypts a record sent by the peer.
+    ///
+    /// A record from a newer epoch moves the receiving side forward, but
+    /// only once it has authenticated. Records from the previous epoch are
+    /// accepted until the overlap window closes; anything older fails with
+    /// [`CryptoError::UnknownKeyId`].
+    pub fn decrypt(&mut self, record: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let now = Instant::now();
+        if self
+            .previous
+            .as_ref()
+            .is_some_and(|previous| now >= previous.expires)
+        {
+            self.previous = None;
+        }
+
+        let epoch = Header::parse(record)?.key_id;
+        match epoch.cmp(&self.receive_chain.epoch) {
+            Ordering::Equal => self.receive.decrypt_bytes(record, aad),
+            Ordering::Less => match &self.previous {
+                Some(previous) if previous.engine.key_id() == epoch => {
+                    previous.engine.decrypt_bytes(record, aad)
+                }
+                _ => Err(CryptoError::UnknownKeyId { key_id: epoch }),
+            },
+            Ordering::Greater => {
+                if epoch - self.receive_chain.epoch > MAX_EPOCH_SKIP {
+                    return Err(CryptoError::UnknownKeyId { key_id: epoch });
+                }
+                let mut chain = self.receive_chain.duplicate();
+                while chain.epoch < epoch {
+                    chain.advance()?;
+                }
+                let engine = chain.engine()?.with_replay_protection();
+                let plaintext = engine.decrypt_bytes(record, aad)?;
+
+                self.previous = Some(Previous {
+                    engine: mem::replace(&mut self.receive, engine),
+                    expires: now + self.policy.overlap,
+                });
+                self.receive_chain = chain;
+                Ok(plaintext)
+            }
+        }
+    }
+}
+
+impl fmt::Debug for RekeyingSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RekeyingSession")
+            .field("role", &self.role)
+            .field("policy", &self.policy)
+            .field("send_epoch", &self.send_chain.epoch)
+            .field("receive_epoch", &self.receive_chain.epoch)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: [u8; 32] = [0x17; 32];
+
+    fn policy() -> RekeyPolicy {
+        RekeyPolicy {
+            max_messages: None,
+            max_bytes: None,
+            max_age: None,
+            overlap: Duration::from_secs(60),
+        }
+    }
+
+    fn pair(policy: RekeyPolicy) -> (RekeyingSession, RekeyingSession) {
+        (
+            RekeyingSession::from_secret(&SECRET, Role::Initiator, policy).unwrap(),
+            RekeyingSession::from_secret(&SECRET, Role::Responder, policy).unwrap(),
+        )
+    }
+
+    fn epoch_of(record: &[u8]) -> u32 {
+        Header::parse(record).unwrap().key_id
+    }
+
+    #[test]
+    fn rekeys_after_message_limit() {
+        let (mut client, mut server) = pair(RekeyPolicy {
+            max_messages: Some(3),
+            ..policy()
+        });
+        let mut epochs = Vec::new();
+        for i in 0..7u8 {
+            let record = client.encrypt(&[i], b"").unwrap();
+            epochs.push(epoch_of(&record));
+            assert_eq!(server.decrypt(&record, b"").unwrap(), [i]);
+        }
+        assert_eq!(epochs, [0, 0, 0, 1, 1, 1, 2]);
+        assert_eq!(client.send_epoch(), 2);
+        assert_eq!(server.receive_epoch(), 2);
+
+        // The other direction is unaffected.
+        assert_eq!(server.send_epoch(), 0);
+        let record = server.encrypt(b"reply", b"").unwrap();
+        assert_eq!(client.decrypt(&record, b"").unwrap(), b"reply");
+    }
+
+    #[test]
+    fn rekeys_after_byte_limit() {
+        let (mut client, _) = pair(RekeyPolicy {
+            max_bytes: Some(100),
+            ..policy()
+        });
+        client.encrypt(&[0u8; 60], b"").unwrap();
+        client.encrypt(&[0u8; 40], b"").unwrap();
+        assert_eq!(client.send_epoch(), 0);
+        assert!(client.needs_rekey(1));
+        client.encrypt(&[0u8; 1], b"").unwrap();
+        assert_eq!(client.send_epoch(), 1);
+    }
+
+    #[test]
+    fn rekeys_after_max_age() {
+        let (mut client, _) = pair(RekeyPolicy {
+            max_age: Some(Duration::ZERO),
+            ..policy()
+        });
+        // An unused key is never replaced.
+        assert!(!client.needs_rekey(0));
+        client.encrypt(b"a", b"").unwrap();
+        client.encrypt(b"b", b"").unwrap();
+        assert_eq!(client.send_epoch(), 1);
+    }
+
+    #[test]
+    fn previous_epoch_accepted_during_overlap() {
+        let (mut client, mut server) = pair(policy());
+        let late = client.encrypt(b"late", b"").unwrap();
+        client.rekey().unwrap();
+        let fresh = client.encrypt(b"fresh", b"").unwrap();
+
+        assert_eq!(server.decrypt(&fresh, b"").unwrap(), b"fresh");
+        assert_eq!(server.decrypt(&late, b"").unwrap(), b"late");
+        assert_eq!(
+            server.decrypt(&late, b""),
+            Err(CryptoError::Replayed { sequence: 0 })
+        );
+    }
+
+    #[test]
+    fn previous_epoch_rejected_after_overlap() {
+        let (mut client, mut server) = pair(RekeyPolicy {
+            overlap: Duration::ZERO,
+            ..policy()
+        });
+        let late = client.encrypt(b"late", b"").unwrap();
+        client.rekey().unwrap();
+        client.rekey().unwrap();
+        server
+            .decrypt(&client.encrypt(b"fresh", b"").unwrap(), b"")
+            .unwrap();
+        assert_eq!(
+            server.decrypt(&late, b""),
+            Err(CryptoError::UnknownKeyId { key_id: 0 })
+        );
+    }
+
+    #[test]
+    fn forged_epoch_does_not_advance_receiver() {
+        let (mut client, mut server) = pair(policy());
+        client.rekey().unwrap();
+        let mut record = client.encrypt(b"data", b"").unwrap();
+        *record.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            server.decrypt(&record, b""),
+            Err(CryptoError::AuthenticationFailed)
+        );
+        assert_eq!(server.receive_epoch(), 0);
+
+        for _ in 0..MAX_EPOCH_SKIP {
+            client.rekey().unwrap();
+        }
+        let record = client.encrypt(b"far", b"").unwrap();
+        assert_eq!(
+            server.decrypt(&record, b""),
+            Err(CryptoError::UnknownKeyId {
+                key_id: MAX_EPOCH_SKIP + 1
+            })
+        );
+    }
+
+    #[test]
+    fn epochs_use_distinct_keys() {
+        let a = Chain::new(&SECRET, Role::Initiator.send_label()).unwrap();
+        let mut b = a.duplicate();
+        b.advance().unwrap();
+        let record = a.engine().unwrap().encrypt_bytes(b"data", b"").unwrap();
+        let other = b.engine().unwrap().with_key_id(0);
+        assert_eq!(
+            other.decrypt_bytes(&record, b""),
+            Err(CryptoError::AuthenticationFailed)
+        );
+    }
+
+    #[test]
+    fn session_from_handshake_rekeys() {
+        use crate::handshake::{Handshake, StaticKeypair};
+
+        let server_static = StaticKeypair::generate();
+        let mut initiator =
+            Handshake::ik_initiator(&StaticKeypair::generate(), &server_static.public_key());
+        let mut responder = Handshake::ik_responder(&server_static);
+        responder
+            .read_message(&initiator.write_message(b"").unwrap())
+            .unwrap();
+        initiator
+            .read_message(&responder.write_message(b"").unwrap())
+            .unwrap();
+
+        let policy = RekeyPolicy {
+            max_messages: Some(1),
+            ..policy()
+        };
+        let mut client =
+            RekeyingSession::from_handshake(initiator.finish().unwrap(), policy).unwrap();
+        let mut server =
+            RekeyingSession::from_handshake(responder.finish().unwrap(), policy).unwrap();
+        assert_eq!(client.role(), Role::Initiator);
+        assert_eq!(server.role(), Role::Responder);
+
+        for (epoch, message) in [b"one", b"two"].into_iter().enumerate() {
+            let record = client.encrypt(message, b"").unwrap();
+            assert_eq!(epoch_of(&record), epoch as u32);
+            assert_eq!(server.decrypt(&record, b"").unwrap(), message);
+        }
+        let record = server.encrypt(b"back", b"").unwrap();
+        assert_eq!(client.decrypt(&record, b"").unwrap(), b"back");
+    }
+}
@@ -0,0 +1,162 @@
+//! Receiver-side anti-replay window over record sequence numbers.
+//!
+//! This is the bitmap scheme used by IPsec and WireGuard (RFC 6479): the
+//! window remembers which of the most recent [`WINDOW_SIZE`] sequence numbers
+//! have been accepted. Anything newer slides the window forward, anything
+//! inside it is accepted once, and anything older is rejected outright.
+
+use crate::error::CryptoError;
+
+const WORDS: usize = 32;
+const WORD_BITS: u64 = u64::BITS as u64;
+
+/// Number of sequence numbers behind the highest accepted one that are still
+/// tracked. Older packets are rejected as too old.
+///
+/// One word of the ring is always being recycled, so the usable window is a
+/// word short of the bitmap size.
+pub const WINDOW_SIZE: u64 = (WORDS as u64 - 1) * WORD_BITS;
+
+/// Sliding bitmap of recently accepted sequence numbers.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayWindow {
+    bitmap: [u64; WORDS],
+    top: Option<u64>,
+}
+
+impl ReplayWindow {
+    /// Creates a window that has not accepted anything yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the highest sequence number accepted so far.
+    pub fn highest(&self) -> Option<u64> {
+        self.top
+    }
+
+    /// Checks whether `sequence` would be accepted, without recording it.
+    ///
+    /// Use this to drop obvious replays before spending time on decryption;
+    /// only call [`update`](Self::update) once the record has authenticated.
+    pub fn check(&self, sequence: u64) -> Result<(), CryptoError> {
+        let Some(top) = self.top else {
+            return Ok(());
+        };
+        if sequence > top {
+            return Ok(());
+        }
+        if top - sequence >= WINDOW_SIZE {
+            return Err(CryptoError::TooOld { sequence });
+        }
+        let (word, bit) = position(sequence);
+        if self.bitmap[word] & bit != 0 {
+            return Err(CryptoError::Replayed { sequence });
+        }
+        Ok(())
+    }
+
+    /// Records `sequence` as accepted, sliding the window forward if needed.
+    ///
+    /// Fails without changing the window if `sequence` is a duplicate or too
+    /// old.
+    pub fn update(&mut self, sequence: u64) -> Result<(), CryptoError> {
+        self.check(sequence)?;
+
+        match self.top {
+            Some(top) if sequence <= top => {}
+            Some(top) => {
+                // Clear the words the window slides over so stale bits from
+                // a previous lap of the ring are not mistaken for replays.
+                let current = top / WORD_BITS;
+                let target = sequence / WORD_BITS;
+                let steps = (target - current).min(WORDS as u64);
+                for step in 1..=steps {
+                    self.bitmap[((current + step) % WORDS as u64) as usize] = 0;
+                }
+                self.top = Some(sequence);
+            }
+            None => self.top = Some(sequence),
+        }
+
+        let (word, bit) = position(sequence);
+        self.bitmap[word] |= bit;
+        Ok(())
+    }
+}
+
+fn position(sequence: u64) -> (usize, u64) {
+    let word = ((sequence / WORD_BITS) % WORDS as u64) as usize;
+    (word, 1 << (sequence % WORD_BITS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_in_order_sequence() {
+        let mut window = ReplayWindow::new();
+        for sequence in 0..5000 {
+            window.update(sequence).unwrap();
+        }
+        assert_eq!(window.highest(), Some(4999));
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        let mut window = ReplayWindow::new();
+        window.update(10).unwrap();
+        assert_eq!(
+            window.update(10),
+            Err(CryptoError::Replayed { sequence: 10 })
+        );
+        assert_eq!(
+            window.check(10),
+            Err(CryptoError::Replayed { sequence: 10 })
+        );
+    }
+
+    #[test]
+    fn accepts_reordered_packets_once() {
+        let mut window = ReplayWindow::new();
+        window.update(100).unwrap();
+        window.update(98).unwrap();
+        window.update(99).unwrap();
+        assert_eq!(
+            window.update(98),
+            Err(CryptoError::Replayed { sequence: 98 })
+        );
+        window.update(101).unwrap();
+    }
+
+    #[test]
+    fn rejects_packets_older_than_window() {
+        let mut window = ReplayWindow::new();
+        window.update(WINDOW_SIZE + 10).unwrap();
+        assert_eq!(window.update(10), Err(CryptoError::TooOld { sequence: 10 }));
+        window.update(11).unwrap();
+    }
+
+    #[test]
+    fn large_jump_clears_stale_bits() {
+        let mut window = ReplayWindow::new();
+        window.update(5).unwrap();
+        // Same ring slot as 5, one full lap later.
+        let lapped = 5 + WORDS as u64 * WORD_BITS;
+        window.update(lapped).unwrap();
+        window.update(lapped - 1).unwrap();
+        assert_eq!(
+            window.update(lapped),
+            Err(CryptoError::Replayed { sequence: lapped })
+        );
+    }
+
+    #[test]
+    fn check_does_not_record() {
+        let mut window = ReplayWindow::new();
+        window.check(3).unwrap();
+        window.check(3).unwrap();
+        window.update(3).unwrap();
+    }
+}
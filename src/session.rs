@@ -0,0 +1,236 @@
+//! Two-way sessions with a separate engine per direction.
+//!
+//! Sharing one [`CryptoEngine`] between both directions lets an attacker
+//! bounce a packet back at its sender, where it decrypts fine. A [`Session`]
+//! instead holds one engine for each direction:
+//!
+//! * each direction has its own key, derived from the shared secret with
+//!   its own HKDF label, and
+//! * each direction binds its own label into the AAD of every record (see
+//!   [`CryptoEngine::with_aad_label`]).
+//!
+//! A record sent by either peer therefore only opens on the other peer's
+//! receiving side; reflected records and records replayed into the wrong
+//! direction fail authentication.
+
+use crate::engine::CryptoEngine;
+use crate::error::CryptoError;
+use crate::handshake::TransportKeys;
+use crate::kdf;
+use crate::padding::Padding;
+
+/// Label for traffic sent by the initiator to the responder.
+pub const INITIATOR_TO_RESPONDER: &[u8] = b"vpn-encrypt session initiator->responder";
+
+/// Label for traffic sent by the responder to the initiator.
+pub const RESPONDER_TO_INITIATOR: &[u8] = b"vpn-encrypt session responder->initiator";
+
+/// Which end of a session this peer is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// The peer that started the key exchange.
+    Initiator,
+    /// The peer that answered it.
+    Responder,
+}
+
+impl Role {
+    /// Returns the other peer's role.
+    pub fn peer(self) -> Self {
+        match self {
+            Role::Initiator => Role::Responder,
+            Role::Responder => Role::Initiator,
+        }
+    }
+
+    /// Returns the label of the direction this role sends in.
+    pub fn send_label(self) -> &'static [u8] {
+        match self {
+            Role::Initiator => INITIATOR_TO_RESPONDER,
+            Role::Responder => RESPONDER_TO_INITIATOR,
+        }
+    }
+}
+
+/// A pair of engines, one per direction, for one peer of a session.
+///
+/// The sending engine uses counter nonces and the receiving engine rejects
+/// replays, so each record is accepted at most once, in one direction.
+#[derive(Debug)]
+pub struct Session {
+    role: Role,
+    send: CryptoEngine,
+    receive: CryptoEngine,
+}
+
+impl Session {
+    /// Derives both directions' keys from a secret shared by the two peers.
+    ///
+    /// Both peers call this with the same secret and opposite roles.
+    pub fn from_secret(secret: &[u8], role: Role) -> Result<Self, CryptoError> {
+        let send = kdf::derive_from_secret(secret, None, role.send_label())?;
+        let receive = kdf::derive_from_secret(secret, None, role.peer().send_label())?;
+        Ok(Self::from_engines(
+            role,
+            CryptoEngine::new(&send),
+            CryptoEngine::new(&receive),
+        ))
+    }
+
+    /// Builds a session from the output of a completed
+    /// [`Handshake`](crate::handshake::Handshake), which already holds one
+    /// key per direction.
+    pub fn from_handshake(keys: TransportKeys) -> Self {
+        Self::from_engines(keys.role, keys.send, keys.receive)
+    }
+
+    fn from_engines(role: Role, send: CryptoEngine, receive: CryptoEngine) -> Self {
+        Self {
+            role,
+            send: send.with_counter_nonces().with_aad_label(role.send_label()),
+            receive: receive
+                .with_replay_protection()
+                .with_aad_label(role.peer().send_label()),
+        }
+    }
+
+    /// Pads every record sent to the peer; see
+    /// [`CryptoEngine::with_padding`].
+    pub fn with_padding(mut self, padding: Padding) -> Self {
+        self.send = self.send.with_padding(padding);
+        self
+    }
+
+    /// Returns this peer's role.
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Returns the engine for records sent to the peer, e.g. for
+    /// [`encrypt_in_place`](CryptoEngine::encrypt_in_place).
+    pub fn sender(&self) -> &CryptoEngine {
+        &self.send
+    }
+
+    /// Returns the engine for records received from the peer.
+    pub fn receiver(&self) -> &CryptoEngine {
+        &self.receive
+    }
+
+    /// Encrypts a message for the peer.
+    pub fn encrypt(&self, message: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.send.encrypt_bytes(message, aad)
+    }
+
+    /// Decrypts a record sent by the peer.
+    pub fn decrypt(&self, record: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.receive.decrypt_bytes(record, aad)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SecretKey;
+    use crate::handshake::{Handshake, StaticKeypair};
+
+    fn pair() -> (Session, Session) {
+        let secret = [0x42u8; 32];
+        (
+            Session::from_secret(&secret, Role::Initiator).unwrap(),
+            Session::from_secret(&secret, Role::Responder).unwrap(),
+        )
+    }
+
+    #[test]
+    fn session_round_trip_both_directions() {
+        let (initiator, responder) = pair();
+        let record = initiator.encrypt(b"request", b"aad").unwrap();
+        assert_eq!(responder.decrypt(&record, b"aad").unwrap(), b"request");
+        let record = responder.encrypt(b"response", b"aad").unwrap();
+        assert_eq!(initiator.decrypt(&record, b"aad").unwrap(), b"response");
+    }
+
+    #[test]
+    fn session_rejects_reflected_records() {
+        let (initiator, responder) = pair();
+        let record = initiator.encrypt(b"ping", b"").unwrap();
+        assert_eq!(
+            initiator.decrypt(&record, b""),
+            Err(CryptoError::AuthenticationFailed)
+        );
+        let record = responder.encrypt(b"pong", b"").unwrap();
+        assert_eq!(
+            responder.decrypt(&record, b""),
+            Err(CryptoError::AuthenticationFailed)
+        );
+    }
+
+    #[test]
+    fn session_rejects_replays() {
+        let (initiator, responder) = pair();
+        let record = initiator.encrypt(b"once", b"").unwrap();
+        responder.decrypt(&record, b"").unwrap();
+        assert_eq!(
+            responder.decrypt(&record, b""),
+            Err(CryptoError::Replayed { sequence: 0 })
+        );
+    }
+
+    #[test]
+    fn direction_label_is_bound_even_under_one_key() {
+        let key = SecretKey::from([3u8; 32]);
+        let sender = CryptoEngine::new(&key).with_aad_label(INITIATOR_TO_RESPONDER);
+        let same = CryptoEngine::new(&key).with_aad_label(INITIATOR_TO_RESPONDER);
+        let other = CryptoEngine::new(&key).with_aad_label(RESPONDER_TO_INITIATOR);
+
+        let record = sender.encrypt_bytes(b"data", b"").unwrap();
+        assert_eq!(same.decrypt_bytes(&record, b"").unwrap(), b"data");
+        assert_eq!(
+            other.decrypt_bytes(&record, b""),
+            Err(CryptoError::AuthenticationFailed)
+        );
+    }
+
+    #[test]
+    fn padding_applies_to_sent_records() {
+        let (initiator, responder) = pair();
+        let initiator = initiator.with_padding(Padding::Multiple(128));
+        let record = initiator.encrypt(b"ping", b"").unwrap();
+        assert_eq!(record.len(), initiator.sender().record_len(0));
+        assert_eq!(responder.decrypt(&record, b"").unwrap(), b"ping");
+        let record = responder.encrypt(b"pong", b"").unwrap();
+        assert_eq!(initiator.decrypt(&record, b"").unwrap(), b"pong");
+    }
+
+    #[test]
+    fn different_secrets_do_not_interoperate() {
+        let initiator = Session::from_secret(&[1u8; 32], Role::Initiator).unwrap();
+        let responder = Session::from_secret(&[2u8; 32], Role::Responder).unwrap();
+        let record = initiator.encrypt(b"data", b"").unwrap();
+        assert!(responder.decrypt(&record, b"").is_err());
+    }
+
+    #[test]
+    fn session_from_handshake() {
+        let server = StaticKeypair::generate();
+        let mut initiator =
+            Handshake::ik_initiator(&StaticKeypair::generate(), &server.public_key());
+        let mut responder = Handshake::ik_responder(&server);
+        responder
+            .read_message(&initiator.write_message(b"").unwrap())
+            .unwrap();
+        initiator
+            .read_message(&responder.write_message(b"").unwrap())
+            .unwrap();
+
+        let client = Session::from_handshake(initiator.finish().unwrap());
+        let server = Session::from_handshake(responder.finish().unwrap());
+        assert_eq!(client.role(), Role::Initiator);
+        assert_eq!(server.role(), Role::Responder);
+
+        let record = client.encrypt(b"hello", b"").unwrap();
+        assert_eq!(server.decrypt(&record, b"").unwrap(), b"hello");
+        assert!(client.decrypt(&record, b"").is_err());
+    }
+}
@@ -0,0 +1,638 @@
+//! Chunked encryption for payloads too large to hold in memory.
+//!
+//! This is the STREAM construction (Hoang, Reyhanitabar, Rogaway, Vizár):
+//! the plaintext is cut into fixed-size chunks, and each chunk is sealed
+//! under a nonce made of a random per-stream prefix, a 32-bit chunk counter
+//! and a final-chunk flag:
+//!
+//! ```text
+//! nonce = [prefix (nonce_len - 5 bytes)][counter u32 BE][last flag u8]
+//! ```
+//!
+//! Reordering chunks changes their counters, and cutting the stream short
+//! loses the chunk sealed with the last flag, so both fail authentication.
+//!
+//! A stream starts with its own header, which is authenticated with every
+//! chunk:
+//!
+//! ```text
+//! offset  size  field
+//!      0     4  magic "VPNS"
+//!      4     1  stream format version
+//!      5     1  cipher suite id
+//!      6     4  chunk size (big endian)
+//!     10     4  key id (big endian)
+//!     14     *  nonce prefix
+//! ```
+
+use std::io::{self, Read, Write};
+
+use rand::{RngCore, rngs::OsRng};
+
+use crate::engine::{CryptoEngine, TAG_LEN};
+use crate::error::CryptoError;
+use crate::format::CipherSuite;
+
+/// Magic bytes identifying an encrypted stream.
+pub const STREAM_MAGIC: [u8; 4] = *b"VPNS";
+
+/// Current stream format version.
+pub const STREAM_VERSION: u8 = 1;
+
+/// Default plaintext bytes per chunk.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest chunk size a reader accepts, so a hostile header cannot make it
+/// allocate without bound.
+pub const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+const FIXED_HEADER_LEN: usize = 14;
+const SUFFIX_LEN: usize = 5;
+
+fn header_len(suite: CipherSuite) -> usize {
+    FIXED_HEADER_LEN + suite.nonce_len() - SUFFIX_LEN
+}
+
+fn invalid(e: CryptoError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Shared per-stream state: the engine, the authenticated header and the
+/// chunk counter.
+struct ChunkCipher<'a> {
+    engine: &'a CryptoEngine,
+    aad: Vec<u8>,
+    prefix_len: usize,
+    counter: u32,
+    nonce: [u8; crate::NONCE_LEN],
+}
+
+impl<'a> ChunkCipher<'a> {
+    fn new(engine: &'a CryptoEngine, header: &[u8], aad: &[u8]) -> Self {
+        let prefix_len = engine.suite().nonce_len() - SUFFIX_LEN;
+        let mut nonce = [0u8; crate::NONCE_LEN];
+        nonce[..prefix_len].copy_from_slice(&header[FIXED_HEADER_LEN..]);
+        Self {
+            engine,
+            aad: [header, engine.aad_label(), aad].concat(),
+            prefix_len,
+            counter: 0,
+            nonce,
+        }
+    }
+
+    /// Writes the nonce for the next chunk and advances the counter.
+    fn advance(&mut self, last: bool) -> Result<usize, CryptoError> {
+        let counter = self.counter;
+        self.counter = counter.checked_add(1).ok_or(CryptoError::NonceExhausted)?;
+        let suffix = &mut self.nonce[self.prefix_len..self.prefix_len + SUFFIX_LEN];
+        suffix[..4].copy_from_slice(&counter.to_be_bytes());
+        suffix[4] = last as u8;
+        Ok(self.prefix_len + SUFFIX_LEN)
+    }
+
+    fn seal(&mut self, chunk: &mut Vec<u8>, last: bool) -> Result<(), CryptoError> {
+        let nonce_len = self.advance(last)?;
+        let tag = self
+            .engine
+            .cipher()
+            .seal_in_place(&self.nonce[..nonce_len], &self.aad, chunk)?;
+        chunk.extend_from_slice(&tag);
+        Ok(())
+    }
+
+    fn open(&mut self, chunk: &mut Vec<u8>, last: bool) -> Result<(), CryptoError> {
+        let split = chunk
+            .len()
+            .checked_sub(TAG_LEN)
+            .ok_or(CryptoError::TruncatedStream)?;
+        let mut tag = [0u8; TAG_LEN];
+        tag.copy_from_slice(&chunk[split..]);
+        chunk.truncate(split);
+
+        let nonce_len = self.advance(last)?;
+        let cipher = self.engine.cipher();
+        match cipher.open_in_place(&self.nonce[..nonce_len], &self.aad, chunk, &tag) {
+            // A failed open leaves the chunk sealed. If it opens without the
+            // final flag, the stream was cut at a chunk boundary.
+            Err(CryptoError::AuthenticationFailed) if last => {
+                self.nonce[nonce_len - 1] = 0;
+                match cipher.open_in_place(&self.nonce[..nonce_len], &self.aad, chunk, &tag) {
+                    Ok(()) => Err(CryptoError::TruncatedStream),
+                    Err(e) => Err(e),
+                }
+            }
+            result => result,
+        }
+    }
+}
+
+/// Why a stream stopped, so the error can be repeated.
+enum Failure {
+    Crypto(CryptoError),
+    Io(io::ErrorKind),
+}
+
+impl Failure {
+    fn of(e: &io::Error) -> Self {
+        match e.get_ref().and_then(|e| e.downcast_ref()) {
+            Some(e) => Failure::Crypto(CryptoError::clone(e)),
+            None => Failure::Io(e.kind()),
+        }
+    }
+
+    fn to_error(&self) -> io::Error {
+        match self {
+            Failure::Crypto(e) => invalid(e.clone()),
+            Failure::Io(kind) => io::Error::new(*kind, "stream failed on an earlier call"),
+        }
+    }
+}
+
+/// Encrypts everything written to it into an underlying writer.
+///
+/// Call [`finish`](Self::finish) when done: it seals the final chunk, and a
+/// stream that is dropped without it will fail to decrypt as truncated.
+/// A sealed chunk may be partly written when the underlying writer fails,
+/// so after any error every later `write` and `finish` fails the same way.
+pub struct StreamEncryptor<'a, W: Write> {
+    inner: W,
+    cipher: ChunkCipher<'a>,
+    chunk_size: usize,
+    buffer: Vec<u8>,
+    failed: Option<Failure>,
+}
+
+impl<'a, W: Write> StreamEncryptor<'a, W> {
+    /// Starts a stream with the default chunk size and writes its header.
+    pub fn new(engine: &'a CryptoEngine, inner: W, aad: &[u8]) -> io::Result<Self> {
+        Self::with_chunk_size(engine, inner, aad, DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Starts a stream with an explicit chunk size and writes its header.
+    pub fn with_chunk_size(
+        engine: &'a CryptoEngine,
+        mut inner: W,
+        aad: &[u8],
+        chunk_size: usize,
+    ) -> io::Result<Self> {
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "chunk size must be between 1 byte and MAX_CHUNK_SIZE",
+            ));
+        }
+
+        let suite = engine.suite();
+        let mut header = vec![0u8; header_len(suite)];
+        header[..4].copy_from_slice(&STREAM_MAGIC);
+        header[4] = STREAM_VERSION;
+        header[5] = suite.id();
+        header[6..10].copy_from_slice(&(chunk_size as u32).to_be_bytes());
+        header[10..14].copy_from_slice(&engine.key_id().to_be_bytes());
+        OsRng.fill_bytes(&mut header[FIXED_HEADER_LEN..]);
+        inner.write_all(&header)?;
+
+        Ok(Self {
+            inner,
+            cipher: ChunkCipher::new(engine, &header, aad),
+            chunk_size,
+            buffer: Vec::with_capacity(chunk_size + TAG_LEN),
+            failed: None,
+        })
+    }
+
+    /// Seals the final chunk, flushes, and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_chunk(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    /// Seals the buffered chunk and writes it out. On failure the encryptor
+    /// is poisoned and the buffer dropped.
+    fn write_chunk(&mut self, last: bool) -> io::Result<()> {
+        if let Some(failure) = &self.failed {
+            return Err(failure.to_error());
+        }
+        let result = match self.cipher.seal(&mut self.buffer, last) {
+            Ok(()) => self.inner.write_all(&self.buffer),
+            Err(e) => Err(invalid(e)),
+        };
+        self.buffer.clear();
+        if let Err(e) = &result {
+            self.failed = Some(Failure::of(e));
+        }
+        result
+    }
+}
+
+impl<W: Write> Write for StreamEncryptor<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(failure) = &self.failed {
+            return Err(failure.to_error());
+        }
+        // A full buffer is only sealed once more data arrives, because the
+        // last chunk has to carry the final flag.
+        if self.buffer.len() == self.chunk_size && !buf.is_empty() {
+            self.write_chunk(false)?;
+        }
+        let take = buf.len().min(self.chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..take]);
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts a stream produced by [`StreamEncryptor`] as it is read.
+///
+/// Reads fail with [`io::ErrorKind::InvalidData`] wrapping a [`CryptoError`]
+/// if a chunk does not authenticate, data follows the final chunk, or the
+/// stream was cut at a chunk boundary ([`CryptoError::TruncatedStream`]).
+/// After any error, including one from the underlying reader, every later
+/// read fails the same way.
+pub struct StreamDecryptor<'a, R: Read> {
+    inner: R,
+    cipher: ChunkCipher<'a>,
+    chunk_size: usize,
+    chunk: Vec<u8>,
+    position: usize,
+    lookahead: Option<u8>,
+    finished: bool,
+    failed: Option<Failure>,
+}
+
+impl<'a, R: Read> StreamDecryptor<'a, R> {
+    /// Reads and validates the stream header.
+    pub fn new(engine: &'a CryptoEngine, mut inner: R, aad: &[u8]) -> io::Result<Self> {
+        let suite = engine.suite();
+        let mut header = vec![0u8; header_len(suite)];
+        inner.read_exact(&mut header).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => invalid(CryptoError::TruncatedStream),
+            _ => e,
+        })?;
+
+        if header[..4] != STREAM_MAGIC {
+            return Err(invalid(CryptoError::InvalidHeader));
+        }
+        if header[4] != STREAM_VERSION {
+            return Err(invalid(CryptoError::UnsupportedVersion {
+                version: header[4],
+            }));
+        }
+        if header[5] != suite.id() {
+            return Err(invalid(CryptoError::SuiteMismatch { suite: header[5] }));
+        }
+        let chunk_size = u32::from_be_bytes(header[6..10].try_into().unwrap()) as usize;
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(invalid(CryptoError::InvalidHeader));
+        }
+        let key_id = u32::from_be_bytes(header[10..14].try_into().unwrap());
+        if key_id != engine.key_id() {
+            return Err(invalid(CryptoError::UnknownKeyId { key_id }));
+        }
+
+        Ok(Self {
+            inner,
+            cipher: ChunkCipher::new(engine, &header, aad),
+            chunk_size,
+            chunk: Vec::with_capacity(chunk_size + TAG_LEN),
+            position: 0,
+            lookahead: None,
+            finished: false,
+            failed: None,
+        })
+    }
+
+    /// Reads and opens the next chunk into `self.chunk`. On failure the
+    /// decryptor is poisoned and holds no data, so nothing unauthenticated
+    /// can be read out of it.
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let result = self.open_next_chunk();
+        if let Err(e) = &result {
+            self.chunk.clear();
+            self.position = 0;
+            self.failed = Some(Failure::of(e));
+        }
+        result
+    }
+
+    fn open_next_chunk(&mut self) -> io::Result<()> {
+        let sealed_len = self.chunk_size + TAG_LEN;
+        self.chunk.clear();
+        self.chunk.extend(self.lookahead.take());
+        let seeded = self.chunk.len();
+        self.chunk.resize(sealed_len, 0);
+        let filled = read_full(&mut self.inner, &mut self.chunk, seeded)?;
+        self.chunk.truncate(filled);
+
+        // A chunk is the last one exactly when nothing follows it, so peek
+        // one byte past every full chunk.
+        let mut last = true;
+        if filled == sealed_len {
+            let mut next = [0u8; 1];
+            if read_full(&mut self.inner, &mut next, 0)? == 1 {
+                self.lookahead = Some(next[0]);
+                last = false;
+            }
+        }
+
+        self.cipher.open(&mut self.chunk, last).map_err(invalid)?;
+        self.position = 0;
+        self.finished = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for StreamDecryptor<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            if let Some(failure) = &self.failed {
+                return Err(failure.to_error());
+            }
+            if self.finished {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let n = buf.len().min(self.chunk.len() - self.position);
+        buf[..n].copy_from_slice(&self.chunk[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// Reads into `buf[offset..]` until it is full or the reader hits EOF, and
+/// returns how many bytes of `buf` are filled.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8], offset: usize) -> io::Result<usize> {
+    let mut filled = offset;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Encrypts everything from `reader` into `writer` as one stream and returns
+/// the number of plaintext bytes processed.
+pub fn encrypt<R: Read, W: Write>(
+    engine: &CryptoEngine,
+    reader: &mut R,
+    writer: W,
+    aad: &[u8],
+) -> io::Result<u64> {
+    let mut encryptor = StreamEncryptor::new(engine, writer, aad)?;
+    let copied = io::copy(reader, &mut encryptor)?;
+    encryptor.finish()?;
+    Ok(copied)
+}
+
+/// Decrypts a stream from `reader` into `writer` and returns the number of
+/// plaintext bytes written.
+///
+/// Plaintext is written as each chunk authenticates, so on error `writer`
+/// may already hold a verified prefix of the stream.
+pub fn decrypt<R: Read, W: Write>(
+    engine: &CryptoEngine,
+    reader: R,
+    writer: &mut W,
+    aad: &[u8],
+) -> io::Result<u64> {
+    let mut decryptor = StreamDecryptor::new(engine, reader, aad)?;
+    io::copy(&mut decryptor, writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SecretKey;
+
+    const CHUNK: usize = 32;
+
+    fn engine() -> CryptoEngine {
+        CryptoEngine::new(&SecretKey::from([8u8; 32]))
+    }
+
+    fn seal(engine: &CryptoEngine, plaintext: &[u8]) -> Vec<u8> {
+        let mut encryptor =
+            StreamEncryptor::with_chunk_size(engine, Vec::new(), b"file", CHUNK).unwrap();
+        encryptor.write_all(plaintext).unwrap();
+        encryptor.finish().unwrap()
+    }
+
+    fn open(engine: &CryptoEngine, stream: &[u8]) -> io::Result<Vec<u8>> {
+        let mut plaintext = Vec::new();
+        StreamDecryptor::new(engine, stream, b"file")?.read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    fn crypto_error(e: io::Error) -> CryptoError {
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        e.into_inner()
+            .unwrap()
+            .downcast::<CryptoError>()
+            .map(|e| *e)
+            .unwrap()
+    }
+
+    #[test]
+    fn stream_round_trip_across_chunk_boundaries() {
+        let engine = engine();
+        for len in [0, 1, CHUNK - 1, CHUNK, CHUNK + 1, 3 * CHUNK, 3 * CHUNK + 7] {
+            let plaintext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let stream = seal(&engine, &plaintext);
+            let chunks = len.div_ceil(CHUNK).max(1);
+            assert_eq!(
+                stream.len(),
+                header_len(engine.suite()) + len + chunks * TAG_LEN
+            );
+            assert_eq!(open(&engine, &stream).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn stream_helpers_round_trip_with_default_chunks() {
+        let engine = engine();
+        let plaintext = vec![0x5a; DEFAULT_CHUNK_SIZE * 2 + 100];
+        let mut stream = Vec::new();
+        let copied = encrypt(&engine, &mut &plaintext[..], &mut stream, b"").unwrap();
+        assert_eq!(copied, plaintext.len() as u64);
+
+        let mut opened = Vec::new();
+        decrypt(&engine, &stream[..], &mut opened, b"").unwrap();
+        assert_eq!(opened, plaintext);
+    }
+
+    #[test]
+    fn stream_detects_truncation_at_chunk_boundary() {
+        let engine = engine();
+        let stream = seal(&engine, &[1u8; 3 * CHUNK]);
+        let cut = header_len(engine.suite()) + 2 * (CHUNK + TAG_LEN);
+        let err = open(&engine, &stream[..cut]).unwrap_err();
+        assert_eq!(crypto_error(err), CryptoError::TruncatedStream);
+
+        // Cutting inside a chunk just breaks that chunk's tag.
+        let err = open(&engine, &stream[..stream.len() - 1]).unwrap_err();
+        assert_eq!(crypto_error(err), CryptoError::AuthenticationFailed);
+    }
+
+    #[test]
+    fn stream_without_finish_does_not_decrypt() {
+        let engine = engine();
+        let mut stream = Vec::new();
+        {
+            let mut encryptor =
+                StreamEncryptor::with_chunk_size(&engine, &mut stream, b"file", CHUNK).unwrap();
+            encryptor.write_all(&[2u8; 2 * CHUNK + 1]).unwrap();
+        }
+        let err = open(&engine, &stream).unwrap_err();
+        assert_eq!(crypto_error(err), CryptoError::TruncatedStream);
+    }
+
+    #[test]
+    fn stream_detects_reordered_chunks() {
+        let engine = engine();
+        let plaintext: Vec<u8> = (0..3 * CHUNK).map(|i| i as u8).collect();
+        let mut stream = seal(&engine, &plaintext);
+        let start = header_len(engine.suite());
+        let sealed = CHUNK + TAG_LEN;
+        let (first, second) = stream[start..start + 2 * sealed].split_at(sealed);
+        let swapped = [second, first].concat();
+        stream[start..start + 2 * sealed].copy_from_slice(&swapped);
+
+        let err = open(&engine, &stream).unwrap_err();
+        assert_eq!(crypto_error(err), CryptoError::AuthenticationFailed);
+    }
+
+    #[test]
+    fn stream_rejects_tampering_and_wrong_aad() {
+        let engine = engine();
+        let stream = seal(&engine, b"stream contents");
+
+        let mut tampered = stream.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(open(&engine, &tampered).is_err());
+
+        let mut header = stream.clone();
+        header[6] ^= 1;
+        assert!(open(&engine, &header).is_err());
+
+        let mut opened = Vec::new();
+        let err = StreamDecryptor::new(&engine, &stream[..], b"other")
+            .unwrap()
+            .read_to_end(&mut opened)
+            .unwrap_err();
+        assert_eq!(crypto_error(err), CryptoError::AuthenticationFailed);
+        assert!(opened.is_empty());
+    }
+
+    #[test]
+    fn stream_stays_failed_after_tampered_chunk() {
+        let engine = engine();
+        let mut stream = seal(&engine, &[4u8; 2 * CHUNK]);
+        stream[header_len(engine.suite())] ^= 1;
+
+        let mut decryptor = StreamDecryptor::new(&engine, &stream[..], b"file").unwrap();
+        let mut buf = [0u8; CHUNK];
+        for _ in 0..3 {
+            let err = decryptor.read(&mut buf).unwrap_err();
+            assert_eq!(crypto_error(err), CryptoError::AuthenticationFailed);
+        }
+        assert_eq!(buf, [0u8; CHUNK]);
+    }
+
+    /// Fails the first write after the stream header.
+    #[derive(Debug)]
+    struct FailOnce {
+        written: Vec<u8>,
+        failed: bool,
+    }
+
+    impl Write for FailOnce {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !self.written.is_empty() && !self.failed {
+                self.failed = true;
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stream_encryptor_stays_failed_after_write_error() {
+        let engine = engine();
+        let writer = FailOnce {
+            written: Vec::new(),
+            failed: false,
+        };
+        let mut encryptor = StreamEncryptor::with_chunk_size(&engine, writer, b"file", 4).unwrap();
+        assert_eq!(encryptor.write(b"abcd").unwrap(), 4);
+        let err = encryptor.write(b"e").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        // The writer would accept data now, but the stream cannot resume.
+        let err = encryptor.write(b"e").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        let err = encryptor.finish().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn stream_rejects_trailing_data() {
+        let engine = engine();
+        let mut stream = seal(&engine, &[3u8; CHUNK]);
+        stream.push(0);
+        assert!(open(&engine, &stream).is_err());
+    }
+
+    #[test]
+    fn stream_header_checks_engine() {
+        let engine = engine();
+        let stream = seal(&engine, b"data");
+
+        let other = CryptoEngine::new(&SecretKey::from([8u8; 32])).with_key_id(4);
+        let err = StreamDecryptor::new(&other, &stream[..], b"file")
+            .err()
+            .unwrap();
+        assert_eq!(crypto_error(err), CryptoError::UnknownKeyId { key_id: 0 });
+
+        let err = StreamDecryptor::new(&engine, &b"VPNE"[..], b"file")
+            .err()
+            .unwrap();
+        assert_eq!(crypto_error(err), CryptoError::TruncatedStream);
+
+        let mut bad_magic = stream.clone();
+        bad_magic[0] = b'X';
+        let err = StreamDecryptor::new(&engine, &bad_magic[..], b"file")
+            .err()
+            .unwrap();
+        assert_eq!(crypto_error(err), CryptoError::InvalidHeader);
+    }
+
+    #[test]
+    fn stream_supports_every_suite() {
+        for suite in [
+            CipherSuite::XChaCha20Poly1305,
+            CipherSuite::ChaCha20Poly1305,
+            CipherSuite::Aes256Gcm,
+        ] {
+            let engine = CryptoEngine::with_suite(&SecretKey::from([9u8; 32]), suite);
+            let plaintext = vec![7u8; 2 * CHUNK + 3];
+            let stream = seal(&engine, &plaintext);
+            assert_eq!(stream[5], suite.id());
+            assert_eq!(open(&engine, &stream).unwrap(), plaintext);
+        }
+    }
+}
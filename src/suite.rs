@@ -0,0 +1,254 @@
+//! Pluggable AEAD cipher suites.
+//!
+//! [`CryptoEngine`](crate::CryptoEngine) talks to its cipher through the
+//! [`AeadSuite`] trait and records which suite sealed a record in the header
+//! (see [`CipherSuite`]). Three suites ship with the crate:
+//!
+//! | suite               | id | nonce    | notes                              |
+//! |---------------------|----|----------|------------------------------------|
+//! | XChaCha20-Poly1305  | 1  | 24 bytes | default, safe with random nonces   |
+//! | ChaCha20-Poly1305   | 2  | 12 bytes | RFC 8439                           |
+//! | AES-256-GCM         | 3  | 12 bytes | fast on AES-NI, FIPS-approved      |
+//!
+//! The 12-byte suites should be paired with counter nonces: random 96-bit
+//! nonces risk collisions after about 2^32 records under one key.
+
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
+
+use crate::engine::TAG_LEN;
+use crate::error::CryptoError;
+use crate::format::CipherSuite;
+use crate::key::SecretKey;
+
+/// An AEAD algorithm the engine can seal and open records with.
+///
+/// Implementations must wipe their key schedule when dropped.
+pub trait AeadSuite: Send + Sync {
+    /// Wire id of the suite.
+    fn suite(&self) -> CipherSuite;
+
+    /// Encrypts `buffer` in place under `nonce` and returns the tag.
+    fn seal_in_place(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<[u8; TAG_LEN], CryptoError>;
+
+    /// Verifies `tag` and decrypts `buffer` in place under `nonce`.
+    ///
+    /// Implementations must check the tag before touching `buffer`, so a
+    /// failed call leaves it unchanged.
+    fn open_in_place(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        buffer: &mut [u8],
+        tag: &[u8; TAG_LEN],
+    ) -> Result<(), CryptoError>;
+
+    /// Encrypts `message` under `nonce`, returning ciphertext followed by the
+    /// 16-byte tag.
+    fn seal(&self, nonce: &[u8], message: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let mut sealed = Vec::with_capacity(message.len() + TAG_LEN);
+        sealed.extend_from_slice(message);
+        let tag = self.seal_in_place(nonce, aad, &mut sealed)?;
+        sealed.extend_from_slice(&tag);
+        Ok(sealed)
+    }
+
+    /// Verifies and decrypts `ciphertext` (including its tag) under `nonce`.
+    fn open(&self, nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let split = ciphertext
+            .len()
+            .checked_sub(TAG_LEN)
+            .ok_or(CryptoError::AuthenticationFailed)?;
+        let (message, tag) = ciphertext.split_at(split);
+        let mut opened = message.to_vec();
+        self.open_in_place(
+            nonce,
+            aad,
+            &mut opened,
+            tag.try_into().expect("tag is TAG_LEN bytes"),
+        )?;
+        Ok(opened)
+    }
+}
+
+macro_rules! aead_suite {
+    ($(#[$doc:meta])* $name:ident, $cipher:ty, $suite:expr) => {
+        $(#[$doc])*
+        pub struct $name($cipher);
+
+        impl $name {
+            /// Creates the suite from a 32-byte key.
+            pub fn new(key: &SecretKey) -> Self {
+                Self(<$cipher>::new(key.as_bytes().into()))
+            }
+        }
+
+        impl AeadSuite for $name {
+            fn suite(&self) -> CipherSuite {
+                $suite
+            }
+
+            fn seal_in_place(
+                &self,
+                nonce: &[u8],
+                aad: &[u8],
+                buffer: &mut [u8],
+            ) -> Result<[u8; TAG_LEN], CryptoError> {
+                if nonce.len() != $suite.nonce_len() {
+                    return Err(CryptoError::EncryptionFailed);
+                }
+                self.0
+                    .encrypt_in_place_detached(nonce.into(), aad, buffer)
+                    .map(Into::into)
+                    .map_err(|_| CryptoError::EncryptionFailed)
+            }
+
+            fn open_in_place(
+                &self,
+                nonce: &[u8],
+                aad: &[u8],
+                buffer: &mut [u8],
+                tag: &[u8; TAG_LEN],
+            ) -> Result<(), CryptoError> {
+                if nonce.len() != $suite.nonce_len() {
+                    return Err(CryptoError::AuthenticationFailed);
+                }
+                self.0
+                    .decrypt_in_place_detached(nonce.into(), aad, buffer, tag.into())
+                    .map_err(|_| CryptoError::AuthenticationFailed)
+            }
+        }
+    };
+}
+
+aead_suite!(
+    /// XChaCha20-Poly1305 with 24-byte nonces.
+    XChaCha20Poly1305Suite,
+    XChaCha20Poly1305,
+    CipherSuite::XChaCha20Poly1305
+);
+
+aead_suite!(
+    /// ChaCha20-Poly1305 (RFC 8439) with 12-byte nonces.
+    ChaCha20Poly1305Suite,
+    ChaCha20Poly1305,
+    CipherSuite::ChaCha20Poly1305
+);
+
+aead_suite!(
+    /// AES-256-GCM with 12-byte nonces.
+    Aes256GcmSuite,
+    Aes256Gcm,
+    CipherSuite::Aes256Gcm
+);
+
+impl CipherSuite {
+    /// Instantiates this suite's cipher under `key`.
+    pub fn cipher(self, key: &SecretKey) -> Box<dyn AeadSuite> {
+        match self {
+            CipherSuite::XChaCha20Poly1305 => Box::new(XChaCha20Poly1305Suite::new(key)),
+            CipherSuite::ChaCha20Poly1305 => Box::new(ChaCha20Poly1305Suite::new(key)),
+            CipherSuite::Aes256Gcm => Box::new(Aes256GcmSuite::new(key)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn key(bytes: &[u8]) -> SecretKey {
+        SecretKey::from(<[u8; 32]>::try_from(bytes).unwrap())
+    }
+
+    #[test]
+    fn key_schedules_are_wiped_on_drop() {
+        fn wiped<T: zeroize::ZeroizeOnDrop>() {}
+        wiped::<XChaCha20Poly1305>();
+        wiped::<ChaCha20Poly1305>();
+        wiped::<aes::Aes256>();
+    }
+
+    const SUNSCREEN: &[u8] = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+
+    /// RFC 8439, section 2.8.2.
+    #[test]
+    fn chacha20_poly1305_known_answer() {
+        let suite = ChaCha20Poly1305Suite::new(&key(&hex(
+            "808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f",
+        )));
+        let nonce = hex("070000004041424344454647");
+        let aad = hex("50515253c0c1c2c3c4c5c6c7");
+        let sealed = suite.seal(&nonce, SUNSCREEN, &aad).unwrap();
+        let (body, tag) = sealed.split_at(SUNSCREEN.len());
+        assert_eq!(&body[..16], &hex("d31a8d34648e60db7b86afbc53ef7ec2")[..]);
+        assert_eq!(tag, &hex("1ae10b594f09e26a7e902ecbd0600691")[..]);
+        assert_eq!(suite.open(&nonce, &sealed, &aad).unwrap(), SUNSCREEN);
+    }
+
+    /// McGrew & Viega GCM specification, test case 14.
+    #[test]
+    fn aes_256_gcm_known_answer() {
+        let suite = Aes256GcmSuite::new(&SecretKey::from([0u8; 32]));
+        let nonce = [0u8; 12];
+        let sealed = suite.seal(&nonce, &[0u8; 16], b"").unwrap();
+        assert_eq!(
+            sealed,
+            hex("cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919")
+        );
+    }
+
+    #[test]
+    fn failed_open_leaves_buffer_untouched() {
+        for suite in [
+            CipherSuite::XChaCha20Poly1305,
+            CipherSuite::ChaCha20Poly1305,
+            CipherSuite::Aes256Gcm,
+        ] {
+            let cipher = suite.cipher(&SecretKey::from([1u8; 32]));
+            let nonce = vec![0u8; suite.nonce_len()];
+            let mut buffer = *b"payload";
+            let mut tag = cipher.seal_in_place(&nonce, b"", &mut buffer).unwrap();
+            let sealed = buffer;
+            tag[0] ^= 1;
+            assert!(
+                cipher
+                    .open_in_place(&nonce, b"", &mut buffer, &tag)
+                    .is_err()
+            );
+            assert_eq!(buffer, sealed);
+        }
+    }
+
+    #[test]
+    fn suites_reject_wrong_nonce_length() {
+        for suite in [
+            CipherSuite::XChaCha20Poly1305,
+            CipherSuite::ChaCha20Poly1305,
+            CipherSuite::Aes256Gcm,
+        ] {
+            let cipher = suite.cipher(&SecretKey::from([1u8; 32]));
+            assert_eq!(cipher.suite(), suite);
+            assert_eq!(
+                cipher.seal(&[0u8; 8], b"payload", b""),
+                Err(CryptoError::EncryptionFailed)
+            );
+            let nonce = vec![0u8; suite.nonce_len()];
+            let sealed = cipher.seal(&nonce, b"payload", b"").unwrap();
+            assert_eq!(cipher.open(&nonce, &sealed, b"").unwrap(), b"payload");
+        }
+    }
+}
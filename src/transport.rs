@@ -0,0 +1,328 @@
+//! Encrypted records over UDP.
+//!
+//! [`UdpTransport`] owns a UDP socket and one [`Session`] per peer address.
+//! Each datagram carries exactly one record: payloads are sealed with the
+//! peer's sending engine, and incoming datagrams are opened with the
+//! sending address's receiving engine.
+//!
+//! Datagrams that cannot be attributed to a known peer or fail to decrypt
+//! (forged, corrupted, replayed) are dropped silently and counted, as a VPN
+//! endpoint should not let one bad packet stall its receive loop. See
+//! [`UdpTransport::dropped`].
+//!
+//! With the `tokio` cargo feature, [`AsyncUdpTransport`] offers the same
+//! API on a tokio socket.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+use crate::session::Session;
+
+/// Largest UDP payload over IPv4, and a safe receive buffer size.
+pub const MAX_DATAGRAM_LEN: usize = 65_507;
+
+/// The per-peer state shared by the blocking and async transports.
+#[derive(Debug, Default)]
+struct Peers {
+    sessions: HashMap<SocketAddr, Session>,
+    dropped: u64,
+}
+
+impl Peers {
+    fn seal(&self, payload: &[u8], peer: SocketAddr) -> io::Result<Vec<u8>> {
+        let session = self
+            .sessions
+            .get(&peer)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "no session for peer"))?;
+        session
+            .encrypt(payload, &[])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    /// Decrypts the datagram in `buf[..len]` from `peer`, moving the
+    /// plaintext to the front of `buf`. Returns `None` if it was dropped.
+    fn open(&mut self, buf: &mut [u8], len: usize, peer: SocketAddr) -> Option<usize> {
+        let opened = self.sessions.get(&peer).and_then(|session| {
+            let receiver = session.receiver();
+            let plaintext = receiver.decrypt_in_place(&mut buf[..len], &[]).ok()?;
+            // Session receivers reject legacy records, so the plaintext
+            // always starts right after the header and nonce.
+            Some(receiver.prefix_len()..receiver.prefix_len() + plaintext.len())
+        });
+        match opened {
+            Some(plaintext) => {
+                let plaintext_len = plaintext.len();
+                buf.copy_within(plaintext, 0);
+                Some(plaintext_len)
+            }
+            None => {
+                self.dropped += 1;
+                None
+            }
+        }
+    }
+}
+
+/// A blocking UDP socket that encrypts and decrypts per peer.
+#[derive(Debug)]
+pub struct UdpTransport {
+    socket: UdpSocket,
+    peers: Peers,
+}
+
+impl UdpTransport {
+    /// Binds a UDP socket to `addr`.
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        UdpSocket::bind(addr).map(Self::from_socket)
+    }
+
+    /// Wraps an already bound socket.
+    pub fn from_socket(socket: UdpSocket) -> Self {
+        Self {
+            socket,
+            peers: Peers::default(),
+        }
+    }
+
+    /// Returns the underlying socket, e.g. to set timeouts.
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Returns the local address the socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Registers the session used for traffic with `peer`, replacing and
+    /// returning any previous one.
+    pub fn add_peer(&mut self, peer: SocketAddr, session: Session) -> Option<Session> {
+        self.peers.sessions.insert(peer, session)
+    }
+
+    /// Forgets `peer` and returns its session.
+    pub fn remove_peer(&mut self, peer: SocketAddr) -> Option<Session> {
+        self.peers.sessions.remove(&peer)
+    }
+
+    /// Returns the number of datagrams dropped because they came from an
+    /// unknown address or did not decrypt.
+    pub fn dropped(&self) -> u64 {
+        self.peers.dropped
+    }
+
+    /// Encrypts `payload` for `peer` and sends it as one datagram.
+    ///
+    /// Fails with [`io::ErrorKind::NotConnected`] if `peer` has no session.
+    /// Returns the datagram size.
+    pub fn send_to(&self, payload: &[u8], peer: SocketAddr) -> io::Result<usize> {
+        let record = self.peers.seal(payload, peer)?;
+        self.socket.send_to(&record, peer)
+    }
+
+    /// Receives the next datagram that decrypts, writes its plaintext to
+    /// the front of `buf` and returns its length and sender.
+    ///
+    /// `buf` also holds the record while it is decrypted, so it should be
+    /// [`MAX_DATAGRAM_LEN`] bytes, or at least the path MTU. Datagrams that
+    /// are dropped do not end the call; use a read timeout on
+    /// [`socket`](Self::socket) to bound the wait.
+    pub fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (len, peer) = self.socket.recv_from(buf)?;
+            if let Some(plaintext_len) = self.peers.open(buf, len, peer) {
+                return Ok((plaintext_len, peer));
+            }
+        }
+    }
+}
+
+/// An async UDP socket that encrypts and decrypts per peer.
+///
+/// Same behaviour as [`UdpTransport`], on a tokio socket.
+#[cfg(feature = "tokio")]
+#[derive(Debug)]
+pub struct AsyncUdpTransport {
+    socket: tokio::net::UdpSocket,
+    peers: Peers,
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncUdpTransport {
+    /// Binds a UDP socket to `addr`.
+    pub async fn bind(addr: impl tokio::net::ToSocketAddrs) -> io::Result<Self> {
+        tokio::net::UdpSocket::bind(addr)
+            .await
+            .map(Self::from_socket)
+    }
+
+    /// Wraps an already bound socket.
+    pub fn from_socket(socket: tokio::net::UdpSocket) -> Self {
+        Self {
+            socket,
+            peers: Peers::default(),
+        }
+    }
+
+    /// Returns the underlying socket.
+    pub fn socket(&self) -> &tokio::net::UdpSocket {
+        &self.socket
+    }
+
+    /// Returns the local address the socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Registers the session used for traffic with `peer`, replacing and
+    /// returning any previous one.
+    pub fn add_peer(&mut self, peer: SocketAddr, session: Session) -> Option<Session> {
+        self.peers.sessions.insert(peer, session)
+    }
+
+    /// Forgets `peer` and returns its session.
+    pub fn remove_peer(&mut self, peer: SocketAddr) -> Option<Session> {
+        self.peers.sessions.remove(&peer)
+    }
+
+    /// Returns the number of datagrams dropped because they came from an
+    /// unknown address or did not decrypt.
+    pub fn dropped(&self) -> u64 {
+        self.peers.dropped
+    }
+
+    /// Encrypts `payload` for `peer` and sends it as one datagram.
+    pub async fn send_to(&self, payload: &[u8], peer: SocketAddr) -> io::Result<usize> {
+        let record = self.peers.seal(payload, peer)?;
+        self.socket.send_to(&record, peer).await
+    }
+
+    /// Receives the next datagram that decrypts. See
+    /// [`UdpTransport::recv_from`].
+    pub async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (len, peer) = self.socket.recv_from(buf).await?;
+            if let Some(plaintext_len) = self.peers.open(buf, len, peer) {
+                return Ok((plaintext_len, peer));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::session::Role;
+
+    const SECRET: [u8; 32] = [0x18; 32];
+
+    fn pair() -> (UdpTransport, UdpTransport) {
+        let mut client = UdpTransport::bind("127.0.0.1:0").unwrap();
+        let mut server = UdpTransport::bind("127.0.0.1:0").unwrap();
+        let (client_addr, server_addr) =
+            (client.local_addr().unwrap(), server.local_addr().unwrap());
+        client.add_peer(
+            server_addr,
+            Session::from_secret(&SECRET, Role::Initiator).unwrap(),
+        );
+        server.add_peer(
+            client_addr,
+            Session::from_secret(&SECRET, Role::Responder).unwrap(),
+        );
+        for transport in [&client, &server] {
+            transport
+                .socket()
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+        }
+        (client, server)
+    }
+
+    #[test]
+    fn datagrams_round_trip_over_loopback() {
+        let (mut client, mut server) = pair();
+        let server_addr = server.local_addr().unwrap();
+        let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+
+        let sent = client.send_to(b"ping", server_addr).unwrap();
+        assert!(sent > b"ping".len());
+        let (len, from) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(from, client.local_addr().unwrap());
+
+        server.send_to(b"pong", from).unwrap();
+        let (len, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"pong");
+        assert_eq!(from, server_addr);
+    }
+
+    #[test]
+    fn empty_keepalives_round_trip() {
+        let (client, mut server) = pair();
+        let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+        client.send_to(b"", server.local_addr().unwrap()).unwrap();
+        let (len, from) = server.recv_from(&mut buf).unwrap();
+        assert_eq!((len, from), (0, client.local_addr().unwrap()));
+        assert_eq!(server.dropped(), 0);
+    }
+
+    #[test]
+    fn bad_datagrams_are_dropped() {
+        let (client, mut server) = pair();
+        let server_addr = server.local_addr().unwrap();
+        let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+
+        // Garbage and a replay from the known peer, then garbage from a
+        // stranger, then one valid datagram.
+        client.socket().send_to(b"garbage", server_addr).unwrap();
+        let record = client.peers.seal(b"first", server_addr).unwrap();
+        client.socket().send_to(&record, server_addr).unwrap();
+        client.socket().send_to(&record, server_addr).unwrap();
+        let stranger = UdpSocket::bind("127.0.0.1:0").unwrap();
+        stranger.send_to(&record, server_addr).unwrap();
+        client.send_to(b"second", server_addr).unwrap();
+
+        let (len, _) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"first");
+        let (len, _) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"second");
+        assert_eq!(server.dropped(), 3);
+    }
+
+    #[test]
+    fn sending_to_unknown_peer_fails() {
+        let (client, _server) = pair();
+        let nowhere: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let err = client.send_to(b"data", nowhere).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn async_datagrams_round_trip_over_loopback() {
+        let mut client = AsyncUdpTransport::bind("127.0.0.1:0").await.unwrap();
+        let mut server = AsyncUdpTransport::bind("127.0.0.1:0").await.unwrap();
+        let (client_addr, server_addr) =
+            (client.local_addr().unwrap(), server.local_addr().unwrap());
+        client.add_peer(
+            server_addr,
+            Session::from_secret(&SECRET, Role::Initiator).unwrap(),
+        );
+        server.add_peer(
+            client_addr,
+            Session::from_secret(&SECRET, Role::Responder).unwrap(),
+        );
+        let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+
+        client.send_to(b"ping", server_addr).await.unwrap();
+        let (len, from) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..len], from), (&b"ping"[..], client_addr));
+
+        server.send_to(b"pong", client_addr).await.unwrap();
+        let (len, from) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..len], from), (&b"pong"[..], server_addr));
+    }
+}
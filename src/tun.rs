@@ -0,0 +1,498 @@
+//! Linux TUN devices and a point-to-point tunnel over them.
+//!
+//! A [`TunDevice`] is a layer-3 virtual interface: every IP packet the
+//! kernel routes to it can be read from the device, and every packet
+//! written to it is delivered as if it had arrived on the interface. A
+//! [`Tunnel`] connects one device to a [`UdpTransport`] peer, encrypting
+//! outbound packets and decrypting inbound ones.
+//!
+//! Creating and configuring a device needs `CAP_NET_ADMIN`. The tests that
+//! do so are ignored by default; run them as root inside a fresh network
+//! namespace:
+//!
+//! ```text
+//! unshare -n sh -c 'ip link set lo up && cargo test tun -- --ignored'
+//! ```
+//!
+//! Only IPv4 addresses are configured here; IPv6 can be added to the
+//! interface with the usual tools once it exists.
+
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use crate::transport::{MAX_DATAGRAM_LEN, UdpTransport};
+
+const TUN_PATH: &str = "/dev/net/tun";
+
+/// Settings for a new TUN interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunConfig {
+    name: String,
+    mtu: Option<u32>,
+    address: Option<(Ipv4Addr, u8)>,
+    destination: Option<Ipv4Addr>,
+}
+
+impl TunConfig {
+    /// Starts a configuration for an interface called `name`.
+    ///
+    /// The name may contain `%d` to let the kernel pick a free number (e.g.
+    /// `"tun%d"`), or be empty for the kernel's default.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            mtu: None,
+            address: None,
+            destination: None,
+        }
+    }
+
+    /// Sets the interface MTU.
+    pub fn with_mtu(mut self, mtu: u32) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// Assigns an IPv4 address with a prefix length, e.g. `10.0.0.1/24`.
+    pub fn with_address(mut self, address: Ipv4Addr, prefix_len: u8) -> Self {
+        self.address = Some((address, prefix_len));
+        self
+    }
+
+    /// Sets the address of the other end of the point-to-point link.
+    pub fn with_destination(mut self, destination: Ipv4Addr) -> Self {
+        self.destination = Some(destination);
+        self
+    }
+}
+
+/// An open TUN interface. The interface disappears when this is dropped.
+#[derive(Debug)]
+pub struct TunDevice {
+    file: File,
+    name: String,
+}
+
+impl TunDevice {
+    /// Creates the interface, applies `config` and brings it up.
+    ///
+    /// Packets are read and written without the 4-byte packet information
+    /// prefix (`IFF_NO_PI`), i.e. each read returns exactly one IP packet.
+    pub fn open(config: &TunConfig) -> io::Result<Self> {
+        let mut request = ifreq(&config.name)?;
+        if let Some((_, prefix_len)) = config.address {
+            netmask(prefix_len)?;
+        }
+
+        let file = OpenOptions::new().read(true).write(true).open(TUN_PATH)?;
+        request.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+        // SAFETY: `request` is a valid ifreq and TUNSETIFF only reads and
+        // writes within it.
+        cvt(unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF as _, &mut request) })?;
+
+        // The kernel writes back the final name, e.g. with `%d` replaced.
+        // SAFETY: the kernel NUL-terminates `ifr_name`.
+        let name = unsafe { CStr::from_ptr(request.ifr_name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+        let device = Self { file, name };
+        device.configure(config)?;
+        Ok(device)
+    }
+
+    /// Returns the interface name assigned by the kernel.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the interface's current MTU.
+    pub fn mtu(&self) -> io::Result<u32> {
+        let socket = control_socket()?;
+        let mut request = ifreq(&self.name)?;
+        // SAFETY: SIOCGIFMTU fills in `ifru_mtu` of a valid ifreq.
+        cvt(unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFMTU as _, &mut request) })?;
+        // SAFETY: the ioctl above initialised `ifru_mtu`.
+        Ok(unsafe { request.ifr_ifru.ifru_mtu } as u32)
+    }
+
+    /// Reads one IP packet into `buf` and returns its length.
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.file).read(buf)
+    }
+
+    /// Writes one IP packet to the interface.
+    pub fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        (&self.file).write(packet)
+    }
+
+    fn configure(&self, config: &TunConfig) -> io::Result<()> {
+        let socket = control_socket()?;
+        let fd = socket.as_raw_fd();
+
+        if let Some(mtu) = config.mtu {
+            let mut request = ifreq(&self.name)?;
+            request.ifr_ifru.ifru_mtu = mtu as libc::c_int;
+            // SAFETY: valid ifreq with `ifru_mtu` set.
+            cvt(unsafe { libc::ioctl(fd, libc::SIOCSIFMTU as _, &mut request) })?;
+        }
+        if let Some((address, prefix_len)) = config.address {
+            set_address(fd, &self.name, libc::SIOCSIFADDR, address)?;
+            set_address(fd, &self.name, libc::SIOCSIFNETMASK, netmask(prefix_len)?)?;
+        }
+        if let Some(destination) = config.destination {
+            set_address(fd, &self.name, libc::SIOCSIFDSTADDR, destination)?;
+        }
+
+        let mut request = ifreq(&self.name)?;
+        // SAFETY: SIOCGIFFLAGS fills in `ifru_flags` of a valid ifreq.
+        cvt(unsafe { libc::ioctl(fd, libc::SIOCGIFFLAGS as _, &mut request) })?;
+        // SAFETY: initialised by the ioctl above.
+        let flags = unsafe { request.ifr_ifru.ifru_flags };
+        request.ifr_ifru.ifru_flags = flags | (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+        // SAFETY: valid ifreq with `ifru_flags` set.
+        cvt(unsafe { libc::ioctl(fd, libc::SIOCSIFFLAGS as _, &mut request) })?;
+        Ok(())
+    }
+}
+
+impl AsRawFd for TunDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// Builds an `ifreq` for interface `name`, zeroed otherwise.
+fn ifreq(name: &str) -> io::Result<libc::ifreq> {
+    if name.len() >= libc::IFNAMSIZ || name.bytes().any(|b| b == 0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "interface name must be shorter than IFNAMSIZ and contain no NUL",
+        ));
+    }
+    // SAFETY: ifreq is plain old data, for which all zeroes is valid.
+    let mut request: libc::ifreq = unsafe { mem::zeroed() };
+    for (dst, &src) in request.ifr_name.iter_mut().zip(name.as_bytes()) {
+        *dst = src as libc::c_char;
+    }
+    Ok(request)
+}
+
+/// Converts a prefix length into a netmask, e.g. `24` into `255.255.255.0`.
+fn netmask(prefix_len: u8) -> io::Result<Ipv4Addr> {
+    match prefix_len {
+        0 => Ok(Ipv4Addr::UNSPECIFIED),
+        1..=32 => Ok(Ipv4Addr::from(u32::MAX << (32 - prefix_len))),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "IPv4 prefix length must be at most 32",
+        )),
+    }
+}
+
+fn set_address(
+    fd: RawFd,
+    name: &str,
+    request_code: libc::c_ulong,
+    address: Ipv4Addr,
+) -> io::Result<()> {
+    let mut request = ifreq(name)?;
+    let sockaddr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 0,
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(address).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    // SAFETY: sockaddr_in fits in, and is layout-compatible with the start
+    // of, the sockaddr inside the ifreq union.
+    unsafe {
+        std::ptr::write(
+            &mut request.ifr_ifru.ifru_addr as *mut libc::sockaddr as *mut libc::sockaddr_in,
+            sockaddr,
+        );
+        cvt(libc::ioctl(fd, request_code as _, &mut request))?;
+    }
+    Ok(())
+}
+
+/// Opens a throwaway socket to issue interface ioctls on.
+fn control_socket() -> io::Result<OwnedFd> {
+    // SAFETY: plain socket(2) call; the result is checked before use.
+    let fd = cvt(unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) })?;
+    // SAFETY: `fd` is a freshly opened descriptor owned by nobody else.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn cvt(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+/// A point-to-point tunnel between a TUN device and one transport peer.
+///
+/// Packets the kernel routes into the device are encrypted with the peer's
+/// session and sent as datagrams; datagrams from the peer that decrypt are
+/// written back into the device.
+#[derive(Debug)]
+pub struct Tunnel {
+    device: TunDevice,
+    transport: UdpTransport,
+    peer: SocketAddr,
+}
+
+impl Tunnel {
+    /// Connects `device` to `peer`, which must already have a session in
+    /// `transport`.
+    pub fn new(device: TunDevice, transport: UdpTransport, peer: SocketAddr) -> Self {
+        Self {
+            device,
+            transport,
+            peer,
+        }
+    }
+
+    /// Returns the TUN device.
+    pub fn device(&self) -> &TunDevice {
+        &self.device
+    }
+
+    /// Returns the transport.
+    pub fn transport(&self) -> &UdpTransport {
+        &self.transport
+    }
+
+    /// Reads one packet from the device and sends it to the peer. Returns
+    /// the packet length.
+    pub fn forward_outbound(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.device.recv(buf)?;
+        self.transport.send_to(&buf[..len], self.peer)?;
+        Ok(len)
+    }
+
+    /// Receives one packet from the peer and writes it to the device.
+    /// Returns the packet length.
+    ///
+    /// Datagrams from other addresses are dropped by the transport even if
+    /// it has sessions for them. Empty payloads are keepalives and are not
+    /// written to the device.
+    pub fn forward_inbound(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let (len, from) = self.transport.recv_from(buf)?;
+            if from == self.peer && len > 0 {
+                self.device.send(&buf[..len])?;
+                return Ok(len);
+            }
+        }
+    }
+
+    /// Forwards packets in both directions until an I/O error occurs.
+    ///
+    /// Uses `poll(2)` on the device and the socket, so a single thread
+    /// serves both directions. The socket is switched to non-blocking mode.
+    /// A packet that cannot be sent right away (`WouldBlock`, `ENOBUFS`) is
+    /// dropped, as a congested link would; an error or hang-up reported by
+    /// `poll` on either descriptor ends the loop.
+    pub fn run(&mut self) -> io::Result<()> {
+        self.transport.socket().set_nonblocking(true)?;
+        let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+        let mut fds = [
+            libc::pollfd {
+                fd: self.device.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.transport.socket().as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        loop {
+            // SAFETY: `fds` is a valid array of two pollfd structs.
+            if let Err(e) = cvt(unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) }) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            if fds[1].revents & libc::POLLERR != 0
+                && let Some(e) = self.transport.socket().take_error()?
+            {
+                return Err(e);
+            }
+            check_revents(fds[0].revents, "TUN device")?;
+            check_revents(fds[1].revents, "socket")?;
+            if fds[0].revents & libc::POLLIN != 0 {
+                drop_transient(self.forward_outbound(&mut buf))?;
+            }
+            if fds[1].revents & libc::POLLIN != 0 {
+                // Everything waiting may also have been dropped as invalid.
+                drop_transient(self.forward_inbound(&mut buf))?;
+            }
+        }
+    }
+}
+
+/// Turns an error condition reported by `poll` into an error, rather than
+/// letting `poll` return immediately forever.
+fn check_revents(revents: libc::c_short, what: &str) -> io::Result<()> {
+    if revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) == 0 {
+        return Ok(());
+    }
+    Err(io::Error::other(format!(
+        "poll reported an error or hang-up on the {what}"
+    )))
+}
+
+/// Treats errors that only mean "not now" as a dropped packet.
+fn drop_transient(result: io::Result<usize>) -> io::Result<()> {
+    match result {
+        Err(e)
+            if e.kind() == io::ErrorKind::WouldBlock || e.raw_os_error() == Some(libc::ENOBUFS) =>
+        {
+            Ok(())
+        }
+        result => result.map(drop),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::session::{Role, Session};
+
+    #[test]
+    fn netmask_from_prefix() {
+        assert_eq!(netmask(24).unwrap(), Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(netmask(32).unwrap(), Ipv4Addr::BROADCAST);
+        assert_eq!(netmask(0).unwrap(), Ipv4Addr::UNSPECIFIED);
+        assert!(netmask(33).is_err());
+    }
+
+    #[test]
+    fn transient_send_errors_drop_the_packet() {
+        let would_block = io::Error::from(io::ErrorKind::WouldBlock);
+        assert!(drop_transient(Err(would_block)).is_ok());
+        let no_buffers = io::Error::from_raw_os_error(libc::ENOBUFS);
+        assert!(drop_transient(Err(no_buffers)).is_ok());
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert!(drop_transient(Err(denied)).is_err());
+        assert!(check_revents(libc::POLLIN, "socket").is_ok());
+        assert!(check_revents(libc::POLLIN | libc::POLLHUP, "socket").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_interface_names() {
+        let long = "x".repeat(libc::IFNAMSIZ);
+        let err = TunDevice::open(&TunConfig::new(&long)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = TunDevice::open(&TunConfig::new("a\0b")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    /// Builds a minimal IPv4/UDP packet with a zero UDP checksum.
+    fn udp_packet(src: Ipv4Addr, dst: Ipv4Addr, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+        let total_len = 20 + 8 + payload.len();
+        let mut packet = vec![0u8; total_len];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+        packet[8] = 64;
+        packet[9] = libc::IPPROTO_UDP as u8;
+        packet[12..16].copy_from_slice(&src.octets());
+        packet[16..20].copy_from_slice(&dst.octets());
+        let mut sum: u32 = packet[..20]
+            .chunks(2)
+            .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+            .sum();
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        packet[10..12].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+        packet[20..22].copy_from_slice(&4000u16.to_be_bytes());
+        packet[22..24].copy_from_slice(&dst_port.to_be_bytes());
+        packet[24..26].copy_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        packet[28..].copy_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    #[ignore = "needs CAP_NET_ADMIN; run in a network namespace"]
+    fn device_applies_config() {
+        let config = TunConfig::new("vpntest%d")
+            .with_mtu(1280)
+            .with_address(Ipv4Addr::new(10, 77, 0, 1), 24)
+            .with_destination(Ipv4Addr::new(10, 77, 0, 2));
+        let device = TunDevice::open(&config).unwrap();
+        assert!(device.name().starts_with("vpntest"));
+        assert_eq!(device.mtu().unwrap(), 1280);
+    }
+
+    #[test]
+    #[ignore = "needs CAP_NET_ADMIN; run in a network namespace"]
+    fn tunnel_forwards_both_directions() {
+        let local = Ipv4Addr::new(10, 78, 0, 1);
+        let remote = Ipv4Addr::new(10, 78, 0, 2);
+        let config = TunConfig::new("vpntest%d")
+            .with_mtu(1400)
+            .with_address(local, 24)
+            .with_destination(remote);
+        let device = TunDevice::open(&config).unwrap();
+
+        // The far end of the tunnel is a plain transport on loopback.
+        let secret = [0x19u8; 32];
+        let mut transport = UdpTransport::bind("127.0.0.1:0").unwrap();
+        let mut far = UdpTransport::bind("127.0.0.1:0").unwrap();
+        let far_addr = far.local_addr().unwrap();
+        transport.add_peer(
+            far_addr,
+            Session::from_secret(&secret, Role::Initiator).unwrap(),
+        );
+        far.add_peer(
+            transport.local_addr().unwrap(),
+            Session::from_secret(&secret, Role::Responder).unwrap(),
+        );
+        far.socket()
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut tunnel = Tunnel::new(device, transport, far_addr);
+        let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+
+        // Outbound: a datagram routed into the device reaches the far end
+        // as an encrypted IP packet.
+        let app = UdpSocket::bind((local, 0)).unwrap();
+        app.send_to(b"outbound", (remote, 5000)).unwrap();
+        loop {
+            tunnel.forward_outbound(&mut buf).unwrap();
+            let (len, _) = far.recv_from(&mut buf).unwrap();
+            // Skip anything else the kernel sends through the device, e.g.
+            // IPv6 router solicitations.
+            if buf[0] >> 4 == 4 && buf[9] == libc::IPPROTO_UDP as u8 {
+                assert_eq!(&buf[16..20], &remote.octets());
+                assert_eq!(&buf[len - 8..len], b"outbound");
+                break;
+            }
+        }
+
+        // Inbound: a packet sent by the far end is delivered locally.
+        let app_port = app.local_addr().unwrap().port();
+        let packet = udp_packet(remote, local, app_port, b"inbound");
+        far.send_to(&packet, tunnel.transport().local_addr().unwrap())
+            .unwrap();
+        tunnel.forward_inbound(&mut buf).unwrap();
+        app.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (len, from) = app.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"inbound");
+        assert_eq!(from, SocketAddr::from((remote, 4000)));
+    }
+}
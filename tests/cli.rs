@@ -0,0 +1,228 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("vpn-encrypt-cli-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn run(args: &[&str], stdin: &[u8]) -> Output {
+    run_with_env(args, stdin, &[])
+}
+
+fn run_with_env(args: &[&str], stdin: &[u8], env: &[(&str, &str)]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_vpn-encrypt"))
+        .args(args)
+        .envs(env.iter().copied())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // A command that fails early may exit before reading its input.
+    let _ = child.stdin.take().unwrap().write_all(stdin);
+    child.wait_with_output().unwrap()
+}
+
+fn keygen(dir: &Path, options: &[&str]) -> String {
+    let key = dir.join("key").to_str().unwrap().to_owned();
+    let output = run(&[&["keygen", &key][..], options].concat(), b"");
+    assert!(output.status.success(), "{:?}", output);
+    key
+}
+
+#[test]
+fn keygen_writes_private_key_file_and_refuses_to_overwrite() {
+    let dir = temp_dir("keygen");
+    let key = keygen(&dir, &[]);
+    let text = std::fs::read(&key).unwrap();
+    assert!(text.starts_with(b"VPNK"));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&key).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    assert!(!run(&["keygen", &key], b"").status.success());
+    assert_eq!(std::fs::read(&key).unwrap(), text);
+    assert!(run(&["keygen", "--force", &key], b"").status.success());
+    assert_ne!(std::fs::read(&key).unwrap(), text);
+}
+
+#[test]
+fn encrypt_decrypt_round_trip_over_stdio_in_every_format() {
+    let dir = temp_dir("stdio");
+    let key = keygen(&dir, &[]);
+    for format in ["raw", "hex", "base64"] {
+        let sealed = run(
+            &["encrypt", "-k", &key, "--aad", "tunnel", "-f", format],
+            b"secret payload",
+        );
+        assert!(sealed.status.success(), "{:?}", sealed);
+        let opened = run(
+            &["decrypt", "-k", &key, "--aad", "tunnel", "-f", format],
+            &sealed.stdout,
+        );
+        assert!(opened.status.success(), "{:?}", opened);
+        assert_eq!(opened.stdout, b"secret payload");
+
+        let wrong = run(
+            &["decrypt", "-k", &key, "--aad", "other", "-f", format],
+            &sealed.stdout,
+        );
+        assert!(!wrong.status.success());
+        assert!(wrong.stdout.is_empty());
+    }
+}
+
+#[test]
+fn encrypt_decrypt_round_trip_through_files() {
+    let dir = temp_dir("files");
+    let key = keygen(&dir, &["--suite", "aes256gcm", "--key-id", "7"]);
+    let plain = dir.join("plain");
+    let sealed = dir.join("sealed");
+    let opened = dir.join("opened");
+    std::fs::write(&plain, b"file payload").unwrap();
+
+    let status = run(
+        &[
+            "encrypt",
+            "-k",
+            &key,
+            "-i",
+            plain.to_str().unwrap(),
+            "-o",
+            sealed.to_str().unwrap(),
+        ],
+        b"",
+    )
+    .status;
+    assert!(status.success());
+    let status = run(
+        &[
+            "decrypt",
+            "-k",
+            &key,
+            "-i",
+            sealed.to_str().unwrap(),
+            "-o",
+            opened.to_str().unwrap(),
+        ],
+        b"",
+    )
+    .status;
+    assert!(status.success());
+    assert_eq!(std::fs::read(&opened).unwrap(), b"file payload");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&opened).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
+
+#[test]
+fn inspect_prints_header_fields() {
+    let dir = temp_dir("inspect");
+    let key = keygen(&dir, &["--suite", "chacha20poly1305", "--key-id", "42"]);
+    let sealed = run(&["encrypt", "-k", &key, "-f", "hex"], b"abc");
+    let report = run(&["inspect", "-f", "hex"], &sealed.stdout);
+    assert!(report.status.success());
+    let report = String::from_utf8(report.stdout).unwrap();
+    assert!(report.contains("headered (version 1)"), "{}", report);
+    assert!(report.contains("ChaCha20Poly1305 (id 2)"), "{}", report);
+    assert!(report.contains("key id:     42"), "{}", report);
+    assert!(
+        report.contains("19 bytes (3 payload + 16-byte tag)"),
+        "{}",
+        report
+    );
+}
+
+#[test]
+fn inspect_describes_truncated_and_padded_records() {
+    // Counter-nonce flag, but the record ends inside the nonce.
+    let truncated = format!("56504e45 0101 0001 00000000 {}", "00".repeat(10)).replace(' ', "");
+    let report = run(&["inspect", "-f", "hex"], truncated.as_bytes());
+    assert!(report.status.success());
+    let report = String::from_utf8(report.stdout).unwrap();
+    assert!(report.contains("sequence:   (truncated)"), "{}", report);
+
+    let padded = format!("56504e45 0101 0002 00000000 {}", "00".repeat(24 + 20)).replace(' ', "");
+    let report = run(&["inspect", "-f", "hex"], padded.as_bytes());
+    let report = String::from_utf8(report.stdout).unwrap();
+    assert!(
+        report.contains("20 bytes (4 sealed bytes + 16-byte tag)"),
+        "{}",
+        report
+    );
+}
+
+#[test]
+fn bad_key_file_is_reported() {
+    let dir = temp_dir("badkey");
+    let key = dir.join("key");
+    std::fs::write(&key, "not a key\n").unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&key, std::fs::Permissions::from_mode(0o600)).unwrap();
+    }
+    let output = run(&["encrypt", "-k", key.to_str().unwrap()], b"data");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Invalid key file"));
+}
+
+#[cfg(unix)]
+#[test]
+fn world_readable_key_file_is_refused() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = temp_dir("perms");
+    let key = keygen(&dir, &[]);
+    std::fs::set_permissions(&key, std::fs::Permissions::from_mode(0o644)).unwrap();
+    let output = run(&["encrypt", "-k", &key], b"data");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("accessible by other users"));
+}
+
+#[test]
+fn passphrase_protected_key_round_trip() {
+    let dir = temp_dir("passphrase");
+    let key = dir.join("key").to_str().unwrap().to_owned();
+    let env = [("VPN_TEST_PASSPHRASE", "correct horse")];
+    let args = ["--passphrase-env", "VPN_TEST_PASSPHRASE"];
+    assert!(
+        run_with_env(&[&["keygen", &key][..], &args].concat(), b"", &env)
+            .status
+            .success()
+    );
+
+    let without = run(&["encrypt", "-k", &key], b"data");
+    assert!(!without.status.success());
+    assert!(String::from_utf8_lossy(&without.stderr).contains("passphrase"));
+
+    let sealed = run_with_env(
+        &[&["encrypt", "-k", &key][..], &args].concat(),
+        b"data",
+        &env,
+    );
+    assert!(sealed.status.success(), "{:?}", sealed);
+    let opened = run_with_env(
+        &[&["decrypt", "-k", &key][..], &args].concat(),
+        &sealed.stdout,
+        &env,
+    );
+    assert_eq!(opened.stdout, b"data");
+
+    let wrong = run_with_env(
+        &[&["decrypt", "-k", &key][..], &args].concat(),
+        &sealed.stdout,
+        &[("VPN_TEST_PASSPHRASE", "wrong")],
+    );
+    assert!(!wrong.status.success());
+}
@@ -0,0 +1,244 @@
+use vpn_encrypt::format::{HEADER_LEN, Header};
+use vpn_encrypt::{CryptoEngine, CryptoError, NONCE_LEN, SecretKey, TAG_LEN};
+
+const MESSAGE: &str = "Hello, VPN!";
+const AAD: &str = "vpn-auth";
+
+fn engine() -> CryptoEngine {
+    CryptoEngine::new(&SecretKey::from([0u8; 32]))
+}
+
+fn hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+#[test]
+fn round_trip() {
+    let engine = engine();
+    let record = engine.encrypt(MESSAGE, AAD).unwrap();
+    assert_eq!(
+        record.len(),
+        HEADER_LEN + NONCE_LEN + MESSAGE.len() + TAG_LEN
+    );
+    assert_eq!(engine.decrypt(&record, AAD).unwrap(), MESSAGE);
+}
+
+#[test]
+fn round_trip_message_lengths() {
+    let engine = engine();
+    let messages = [
+        "a",
+        "Hello, VPN World! This is a longer message to test encryption.",
+        "🔒🔑💻",
+    ];
+    for message in messages {
+        let record = engine.encrypt(message, AAD).unwrap();
+        assert_eq!(engine.decrypt(&record, AAD).unwrap(), message);
+    }
+}
+
+#[test]
+fn round_trip_binary_payload_and_aad() {
+    let engine = engine();
+    let packet = [0x45, 0x00, 0x00, 0x1c, 0xff, 0xfe, 0x00, 0x80, 0xc0, 0xa8];
+    let header = [0xff, 0x00, 0x80, 0x1c];
+    let record = engine.encrypt_bytes(&packet, &header).unwrap();
+    assert_eq!(engine.decrypt_bytes(&record, &header).unwrap(), packet);
+}
+
+#[test]
+fn decrypt_rejects_non_utf8_plaintext() {
+    let engine = engine();
+    let record = engine.encrypt_bytes(&[0xff, 0xfe], AAD.as_bytes()).unwrap();
+    assert_eq!(engine.decrypt(&record, AAD), Err(CryptoError::InvalidUtf8));
+}
+
+#[test]
+fn empty_message_round_trip() {
+    let engine = engine();
+    let record = engine.encrypt("", AAD).unwrap();
+    assert_eq!(record.len(), HEADER_LEN + NONCE_LEN + TAG_LEN);
+    assert_eq!(engine.decrypt(&record, AAD).unwrap(), "");
+    assert_eq!(
+        engine.decrypt(&record, "wrong-auth"),
+        Err(CryptoError::AuthenticationFailed)
+    );
+}
+
+#[test]
+fn decrypt_rejects_truncated_input() {
+    let engine = engine();
+    assert_eq!(
+        engine.decrypt(&[1, 2, 3], AAD),
+        Err(CryptoError::TruncatedInput { len: 3 })
+    );
+}
+
+#[test]
+fn decrypt_rejects_input_shorter_than_nonce_and_tag() {
+    let engine = engine();
+    assert_eq!(
+        engine.decrypt(&[0u8; NONCE_LEN], AAD),
+        Err(CryptoError::TruncatedInput { len: NONCE_LEN })
+    );
+    let record = engine.encrypt("", AAD).unwrap();
+    assert_eq!(
+        engine.decrypt(&record[..record.len() - 1], AAD),
+        Err(CryptoError::TruncatedInput {
+            len: record.len() - 1
+        })
+    );
+}
+
+#[test]
+fn decrypt_rejects_wrong_aad() {
+    let engine = engine();
+    let record = engine.encrypt(MESSAGE, AAD).unwrap();
+    for aad in ["wrong-auth", "", "vpn", "vpn-auth-wrong", "123"] {
+        assert_eq!(
+            engine.decrypt(&record, aad),
+            Err(CryptoError::AuthenticationFailed),
+            "AAD {:?} should not authenticate",
+            aad
+        );
+    }
+}
+
+#[test]
+fn decrypt_aad_is_case_sensitive() {
+    let engine = engine();
+    let record = engine.encrypt(MESSAGE, AAD).unwrap();
+    for aad in ["VPN-AUTH", "vpn-AUTH", "Vpn-Auth"] {
+        assert_eq!(
+            engine.decrypt(&record, aad),
+            Err(CryptoError::AuthenticationFailed),
+            "AAD {:?} should not authenticate",
+            aad
+        );
+    }
+}
+
+#[test]
+fn decrypt_rejects_tampered_record() {
+    let engine = engine();
+    let record = engine.encrypt(MESSAGE, AAD).unwrap();
+    for i in 0..HEADER_LEN {
+        let mut tampered = record.clone();
+        tampered[i] ^= 0x01;
+        assert!(
+            engine.decrypt(&tampered, AAD).is_err(),
+            "flipping header byte {} should not decrypt",
+            i
+        );
+    }
+    for i in HEADER_LEN..record.len() {
+        let mut tampered = record.clone();
+        tampered[i] ^= 0x01;
+        assert_eq!(
+            engine.decrypt(&tampered, AAD),
+            Err(CryptoError::AuthenticationFailed),
+            "flipping byte {} should not authenticate",
+            i
+        );
+    }
+}
+
+#[test]
+fn decrypt_rejects_wrong_key() {
+    let record = engine().encrypt(MESSAGE, AAD).unwrap();
+    let other = CryptoEngine::new(&SecretKey::from([1u8; 32]));
+    assert_eq!(
+        other.decrypt(&record, AAD),
+        Err(CryptoError::AuthenticationFailed)
+    );
+}
+
+#[test]
+fn record_header_carries_key_id() {
+    let engine = engine().with_key_id(0x0102_0304);
+    let record = engine.encrypt(MESSAGE, AAD).unwrap();
+    assert_eq!(Header::parse(&record).unwrap().key_id, 0x0102_0304);
+    assert_eq!(engine.decrypt(&record, AAD).unwrap(), MESSAGE);
+}
+
+/// Test vector from draft-irtf-cfrg-xchacha-03, Appendix A.3.1, fed through
+/// the legacy headerless record path.
+#[test]
+fn known_answer_xchacha20_poly1305() {
+    let key: [u8; 32] = hex("808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f")
+        .try_into()
+        .unwrap();
+    let nonce = hex("404142434445464748494a4b4c4d4e4f5051525354555657");
+    let aad = hex("50515253c0c1c2c3c4c5c6c7");
+    let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+    let ciphertext = hex(concat!(
+        "bd6d179d3e83d43b9576579493c0e939572a1700252bfaccbed2902c21396cbb",
+        "731c7f1b0b4aa6440bf3a82f4eda7e39ae64c6708c54c216cb96b72e1213b452",
+        "2f8c9ba40db5d945b11b69b982c1bb9e3f3fac2bc369488f76b2383565d3fff9",
+        "21f9664c97637da9768812f615c68b13b52e",
+    ));
+    let tag = hex("c0875924c1c7987947deafd8780acf49");
+
+    let mut record = nonce;
+    record.extend_from_slice(&ciphertext);
+    record.extend_from_slice(&tag);
+
+    let engine = CryptoEngine::new(&SecretKey::from(key));
+    assert_eq!(engine.decrypt_bytes(&record, &aad).unwrap(), plaintext);
+}
+
+#[test]
+fn derived_engines_interoperate() {
+    let params = vpn_encrypt::kdf::Argon2Params {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+    let a = CryptoEngine::from_passphrase(b"correct horse", b"per-site-salt", &params).unwrap();
+    let b = CryptoEngine::from_passphrase(b"correct horse", b"per-site-salt", &params).unwrap();
+    let record = a.encrypt(MESSAGE, AAD).unwrap();
+    assert_eq!(b.decrypt(&record, AAD).unwrap(), MESSAGE);
+
+    let c = CryptoEngine::from_secret(b"shared secret", b"vpn data").unwrap();
+    let d = CryptoEngine::from_secret(b"shared secret", b"vpn control").unwrap();
+    let record = c.encrypt(MESSAGE, AAD).unwrap();
+    assert_eq!(
+        d.decrypt(&record, AAD),
+        Err(CryptoError::AuthenticationFailed)
+    );
+}
+
+#[test]
+fn structured_aad_binds_every_field() {
+    use vpn_encrypt::aad::Aad;
+    use vpn_encrypt::session::Role;
+
+    let engine = engine();
+    let aad = |sequence| {
+        Aad::new()
+            .protocol_version(1)
+            .session_id(b"tunnel-7")
+            .peer_id(b"gateway")
+            .direction(Role::Initiator)
+            .sequence(sequence)
+    };
+    let record = engine.encrypt(MESSAGE, aad(5)).unwrap();
+    assert_eq!(engine.decrypt(&record, aad(5)).unwrap(), MESSAGE);
+    assert_eq!(
+        engine.decrypt_bytes(&record, &aad(5)).unwrap(),
+        MESSAGE.as_bytes()
+    );
+    for wrong in [
+        aad(6),
+        aad(5).custom("extra", b""),
+        Aad::new().session_id(b"tunnel-7"),
+    ] {
+        assert_eq!(
+            engine.decrypt(&record, &wrong),
+            Err(CryptoError::AuthenticationFailed)
+        );
+    }
+}